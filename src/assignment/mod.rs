@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use rdkafka::consumer::Consumer;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::topic_partition_list::Offset;
+use rdkafka::topic_partition_list::TopicPartitionList;
+
+use checkpoint::Checkpoint;
+use cli::StartPosition;
+
+const METADATA_TIMEOUT_SECS: u64 = 10;
+
+fn topic_partitions<C: ConsumerContext>(
+    consumer: &StreamConsumer<C>,
+    topic: &str,
+) -> Result<Vec<i32>, String> {
+    let metadata = consumer
+        .fetch_metadata(Some(topic), Duration::from_secs(METADATA_TIMEOUT_SECS))
+        .map_err(|err| format!("could not fetch the metadata of {}: {}", topic, err))?;
+    Ok(metadata
+        .topics()
+        .iter()
+        .filter(|metadata_topic| metadata_topic.name() == topic)
+        .flat_map(|metadata_topic| metadata_topic.partitions().iter().map(|p| p.id()))
+        .collect())
+}
+
+/// Assigns `partitions` of every topic (all of their partitions when `None`) to the consumer,
+/// starting from `start`, instead of letting the consumer group balance them. Partitions found in
+/// `checkpoint` resume from there instead.
+pub fn assign<C: ConsumerContext>(
+    consumer: &StreamConsumer<C>,
+    topics: &[&str],
+    partitions: Option<&[i32]>,
+    start: &StartPosition,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<(), String> {
+    let resume = |topic: &str, partition: i32, offset: Offset| {
+        checkpoint
+            .and_then(|checkpoint| checkpoint.offset(topic, partition))
+            .map_or(offset, Offset::Offset)
+    };
+    let offset = match *start {
+        StartPosition::Committed => Offset::Stored,
+        StartPosition::Beginning => Offset::Beginning,
+        StartPosition::Offset(offset) => Offset::Offset(offset),
+        // Looked up below, once the partitions are known to the consumer
+        StartPosition::Timestamp(_) => Offset::Stored,
+    };
+    let mut assignment = TopicPartitionList::new();
+    for &topic in topics {
+        let assigned = match partitions {
+            Some(partitions) => partitions.to_vec(),
+            None => topic_partitions(consumer, topic)?,
+        };
+        for &partition in &assigned {
+            assignment.add_partition_offset(topic, partition, resume(topic, partition, offset));
+        }
+        info!("Assigning {} partitions {:?} from {:?}", topic, assigned, start);
+    }
+    consumer
+        .assign(&assignment)
+        .map_err(|err| format!("could not assign the partitions: {}", err))?;
+
+    if let StartPosition::Timestamp(timestamp) = *start {
+        // Partitions without any message since `timestamp` come back with the end offset.
+        let found = consumer
+            .offsets_for_timestamp(timestamp, Duration::from_secs(METADATA_TIMEOUT_SECS))
+            .map_err(|err| format!("could not look up the offsets for the start timestamp: {}", err))?;
+        let mut assignment = TopicPartitionList::new();
+        for elem in found.elements() {
+            let offset = resume(elem.topic(), elem.partition(), elem.offset());
+            assignment.add_partition_offset(elem.topic(), elem.partition(), offset);
+        }
+        consumer
+            .assign(&assignment)
+            .map_err(|err| format!("could not assign the partitions: {}", err))?;
+    }
+    Ok(())
+}
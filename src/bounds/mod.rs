@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+
+/// Decides when a bounded run is over: after a number of messages, or once every assigned
+/// partition reached its end or the end timestamp.
+pub struct Bounds {
+    exit_on_eof: bool,
+    max_messages: Option<u64>,
+    until_timestamp: Option<i64>,
+    consumed: u64,
+    // partitions that reached the end timestamp, for good
+    past_until: HashSet<(String, i32)>,
+    // partitions that reached their end, until a new message shows up
+    at_eof: HashSet<(String, i32)>,
+}
+
+impl Bounds {
+    pub fn new(
+        exit_on_eof: bool,
+        max_messages: Option<u64>,
+        until_timestamp: Option<i64>,
+    ) -> Bounds {
+        Bounds {
+            exit_on_eof: exit_on_eof,
+            max_messages: max_messages,
+            until_timestamp: until_timestamp,
+            consumed: 0,
+            past_until: HashSet::new(),
+            at_eof: HashSet::new(),
+        }
+    }
+
+    /// Whether the end of a partition means anything, i.e. whether `enable.partition.eof` is
+    /// needed.
+    pub fn watches_eof(&self) -> bool {
+        self.exit_on_eof || self.until_timestamp.is_some()
+    }
+
+    /// Whether a message is within the bounds. Only the messages then processed are `count`ed.
+    pub fn accept(&mut self, topic: &str, partition: i32, timestamp: Option<i64>) -> bool {
+        let key = (topic.to_owned(), partition);
+        if self.past_until.contains(&key) {
+            return false;
+        }
+        if let (Some(until), Some(timestamp)) = (self.until_timestamp, timestamp) {
+            if timestamp >= until {
+                info!("{}/{} reached the end timestamp", topic, partition);
+                self.past_until.insert(key);
+                return false;
+            }
+        }
+        if self.max_messages.map_or(false, |max| self.consumed >= max) {
+            return false;
+        }
+        self.at_eof.remove(&key);
+        true
+    }
+
+    /// Counts a processed message toward `max_messages`.
+    pub fn count(&mut self) {
+        self.consumed += 1;
+    }
+
+    pub fn reached_eof(&mut self, topic: &str, partition: i32) {
+        if self.watches_eof() {
+            info!("{}/{} reached its end", topic, partition);
+            self.at_eof.insert((topic.to_owned(), partition));
+        }
+    }
+
+    /// Forgets which partitions are finished, once the consumer gave them up.
+    pub fn forget_partitions(&mut self) {
+        self.past_until.clear();
+        self.at_eof.clear();
+    }
+
+    /// Whether the run is over, given the partitions currently assigned to the consumer. The
+    /// assignment is only looked up when some partition is finished.
+    pub fn is_done<F>(&self, assignment: F) -> bool
+    where
+        F: FnOnce() -> Vec<(String, i32)>,
+    {
+        if self.max_messages.map_or(false, |max| self.consumed >= max) {
+            return true;
+        }
+        if self.past_until.is_empty() && self.at_eof.is_empty() {
+            return false;
+        }
+        let assignment = assignment();
+        !assignment.is_empty()
+            && assignment
+                .iter()
+                .all(|key| self.past_until.contains(key) || self.at_eof.contains(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use bounds::Bounds;
+
+    fn assignment() -> Vec<(String, i32)> {
+        vec![("input".to_owned(), 0), ("input".to_owned(), 1)]
+    }
+
+    #[test]
+    fn it_stops_after_max_messages() {
+        let mut bounds = Bounds::new(false, Some(2), None);
+
+        assert!(bounds.accept("input", 0, None));
+        bounds.count();
+        assert!(!bounds.is_done(assignment));
+        // accepted but sampled out, so not counted
+        assert!(bounds.accept("input", 1, None));
+        assert!(!bounds.is_done(assignment));
+        assert!(bounds.accept("input", 1, None));
+        bounds.count();
+        assert!(bounds.is_done(assignment));
+        assert!(!bounds.accept("input", 0, None));
+    }
+
+    #[test]
+    fn it_stops_once_every_partition_reached_its_end() {
+        let mut bounds = Bounds::new(true, None, None);
+        bounds.reached_eof("input", 0);
+        assert!(!bounds.is_done(assignment));
+
+        bounds.reached_eof("input", 1);
+        assert!(bounds.accept("input", 0, None));
+        assert!(!bounds.is_done(assignment));
+
+        bounds.reached_eof("input", 0);
+        assert!(bounds.is_done(assignment));
+    }
+
+    #[test]
+    fn it_skips_messages_past_the_end_timestamp() {
+        let mut bounds = Bounds::new(false, None, Some(1000));
+
+        assert!(bounds.accept("input", 0, Some(999)));
+        assert!(!bounds.accept("input", 0, Some(1000)));
+        assert!(!bounds.accept("input", 0, Some(10)));
+        bounds.reached_eof("input", 1);
+        assert!(bounds.is_done(assignment));
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+/// The offsets to resume a run from, kept in a local file instead of the consumer group: one
+/// `topic partition offset` line per partition, the offset being the next one to consume.
+pub struct Checkpoint {
+    path: String,
+    offsets: BTreeMap<(String, i32), i64>,
+}
+
+fn parse_checkpoint(contents: &str) -> Result<BTreeMap<(String, i32), i64>, String> {
+    let mut offsets = BTreeMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(format!("Invalid checkpoint line: {}", line));
+        }
+        match (fields[1].parse::<i32>(), fields[2].parse::<i64>()) {
+            (Ok(partition), Ok(offset)) => {
+                offsets.insert((fields[0].to_owned(), partition), offset);
+            }
+            _ => return Err(format!("Invalid checkpoint line: {}", line)),
+        }
+    }
+    Ok(offsets)
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint at `path`. A missing file is an empty checkpoint.
+    pub fn load(path: &str) -> Result<Checkpoint, String> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut contents)
+                    .map_err(|err| format!("Could not read {}: {}", path, err))?;
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(format!("Could not open {}: {}", path, err)),
+        }
+        Ok(Checkpoint {
+            path: path.to_owned(),
+            offsets: parse_checkpoint(&contents)?,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn offset(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.offsets.get(&(topic.to_owned(), partition)).cloned()
+    }
+
+    /// Records the offsets that moved, as `(topic, partition, offset)`.
+    pub fn update(&mut self, offsets: &[(String, i32, i64)]) {
+        for &(ref topic, partition, offset) in offsets {
+            self.offsets.insert((topic.clone(), partition), offset);
+        }
+    }
+
+    /// Writes the checkpoint next to its file and renames it over, so that a crash never leaves
+    /// a partial checkpoint behind. The directory is synced too, or the rename itself could be
+    /// lost.
+    pub fn save(&self) -> io::Result<()> {
+        let temporary_path = format!("{}.tmp", self.path);
+        {
+            let mut file = File::create(&temporary_path)?;
+            for (&(ref topic, partition), offset) in &self.offsets {
+                writeln!(file, "{} {} {}", topic, partition, offset)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temporary_path, &self.path)?;
+        let directory = match Path::new(&self.path).parent() {
+            Some(directory) if directory != Path::new("") => directory,
+            _ => Path::new("."),
+        };
+        File::open(directory)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use checkpoint::Checkpoint;
+    use checkpoint::parse_checkpoint;
+
+    #[test]
+    fn it_parses_a_checkpoint() {
+        let offsets = parse_checkpoint("orders 0 42\norders 3 7\n\nrefunds 0 1\n").unwrap();
+        assert_eq!(offsets.get(&("orders".to_owned(), 3)), Some(&7));
+        assert_eq!(offsets.len(), 3);
+        assert!(parse_checkpoint("orders 0").is_err());
+        assert!(parse_checkpoint("orders zero 42").is_err());
+    }
+
+    #[test]
+    fn it_resumes_from_a_saved_checkpoint() {
+        let path = env::temp_dir().join(format!("kafka-jq-checkpoint-test-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut checkpoint = Checkpoint::load(path).unwrap();
+        assert_eq!(checkpoint.offset("orders", 0), None);
+        checkpoint.update(&[("orders".to_owned(), 0, 42), ("orders".to_owned(), 1, 7)]);
+        checkpoint.save().unwrap();
+        checkpoint.update(&[("orders".to_owned(), 0, 50)]);
+        checkpoint.save().unwrap();
+
+        let checkpoint = Checkpoint::load(path).unwrap();
+        assert_eq!(checkpoint.offset("orders", 0), Some(50));
+        assert_eq!(checkpoint.offset("orders", 1), Some(7));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,6 +1,21 @@
+extern crate chrono;
 extern crate clap;
+extern crate regex;
 
-use self::clap::{App, Arg};
+use self::chrono::DateTime;
+use self::chrono::NaiveDate;
+use self::chrono::NaiveDateTime;
+use self::clap::{App, Arg, ArgMatches};
+use self::regex::Regex;
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+use std::time::Duration;
+
+use error::ErrorClass;
+use jq::jq_check;
+use properties::{from_java_properties, parse_properties, parse_property};
 
 pub enum SerializationType {
     JSON,
@@ -12,19 +27,250 @@ pub struct TopicMetadata<'a> {
     pub serialization: SerializationType,
 }
 
+/// The topics to consume. Names starting with `^` are patterns, which librdkafka keeps matching
+/// against the topics of the cluster, including the ones created later.
+pub struct InputTopics<'a> {
+    pub topics: Vec<TopicMetadata<'a>>,
+    // compiled patterns, with the index of their topic
+    patterns: Vec<(Regex, usize)>,
+}
+impl<'a> InputTopics<'a> {
+    pub fn names(&self) -> Vec<&'a str> {
+        self.topics.iter().map(|topic| topic.name).collect()
+    }
+
+    pub fn has_patterns(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    /// The serialization of a consumed topic, which was either named or matched by a pattern.
+    pub fn serialization(&self, topic: &str) -> Option<&SerializationType> {
+        self.topics
+            .iter()
+            .find(|metadata| metadata.name == topic)
+            .or_else(|| {
+                self.patterns
+                    .iter()
+                    .find(|&&(ref pattern, _)| pattern.is_match(topic))
+                    .map(|&(_, index)| &self.topics[index])
+            })
+            .map(|metadata| &metadata.serialization)
+    }
+}
+
+pub struct TopicRouting<'a> {
+    pub default_topic: Option<&'a str>,
+    pub topic_expression: Option<&'a str>,
+    pub allowed_topics: Option<Vec<&'a str>>,
+    pub fallback_topic: Option<&'a str>,
+}
+
 pub enum SinkMetadata<'a> {
     StdOut,
     SinkTopic { metadata: TopicMetadata<'a> },
+    DynamicTopic {
+        serialization: SerializationType,
+        routing: TopicRouting<'a>,
+    },
 }
 impl<'a> SinkMetadata<'a> {
     pub fn serialization(&self) -> &SerializationType {
         match self {
             &SinkMetadata::StdOut {} => &SerializationType::JSON,
             &SinkMetadata::SinkTopic { ref metadata } => &metadata.serialization,
+            &SinkMetadata::DynamicTopic {
+                ref serialization, ..
+            } => serialization,
+        }
+    }
+
+    pub fn topic_expression(&self) -> Option<&'a str> {
+        match self {
+            &SinkMetadata::DynamicTopic { ref routing, .. } => routing.topic_expression,
+            _ => None,
+        }
+    }
+}
+
+pub struct Route<'a> {
+    pub jq_expression: &'a str,
+    pub sink: SinkMetadata<'a>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    Skip,
+    DeadLetter,
+    Stop,
+}
+
+pub struct ErrorPolicy<'a> {
+    pub on_decode_error: ErrorAction,
+    pub on_jq_error: ErrorAction,
+    pub on_serialization_error: ErrorAction,
+    pub on_routing_error: ErrorAction,
+    pub on_delivery_error: ErrorAction,
+    pub dead_letter_topic: Option<&'a str>,
+}
+impl<'a> ErrorPolicy<'a> {
+    pub fn action(&self, class: ErrorClass) -> ErrorAction {
+        match class {
+            ErrorClass::Decode => self.on_decode_error,
+            ErrorClass::JqRuntime => self.on_jq_error,
+            ErrorClass::Serialization => self.on_serialization_error,
+            ErrorClass::Routing => self.on_routing_error,
+        }
+    }
+}
+
+/// How often, and how patiently, to produce a result again after a retriable delivery error.
+pub struct DeliveryPolicy {
+    /// Including the first one
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl DeliveryPolicy {
+    /// The delay before the attempt following `attempt`, doubling every time.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(0);
+        match self.backoff.checked_mul(factor) {
+            Some(backoff) if factor > 0 => backoff.min(self.max_backoff),
+            _ => self.max_backoff,
         }
     }
 }
 
+/// How output records are spread over the partitions of their topic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Partitioner {
+    /// librdkafka's default, `consistent_random`
+    Default,
+    /// Hash the key with murmur2 like the Java clients, random partition without a key
+    Murmur2,
+    /// Same partition as the input message
+    KeepInput,
+    /// The `partition` field of the output envelope, librdkafka's default without one
+    Jq,
+}
+
+/// What to do with input messages without a payload, i.e. tombstones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TombstonePolicy {
+    Skip,
+    /// Produce a tombstone with the same key to the output topic of every route
+    PassThrough,
+    /// Run the jq programs on `null`, with the key as `$key`; their results keep the input key
+    /// unless they set one
+    Null,
+}
+
+/// Where to start consuming. Anything but `Committed`, or an explicit list of partitions, assigns
+/// the partitions manually instead of joining the consumer group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPosition {
+    Committed,
+    Beginning,
+    Offset(i64),
+    /// Milliseconds since the epoch
+    Timestamp(i64),
+}
+
+/// Which input messages must have their results produced in consumption order. Messages
+/// outside of the same group are still produced as soon as they are processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderingMode {
+    Unordered,
+    Partition,
+    Key,
+}
+
+/// Which input messages are processed: all of them, a random fraction of them, or the fraction
+/// whose sampling key, picked by a jq expression, hashes below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling<'a> {
+    All,
+    Random(f64),
+    ByKey(&'a str, f64),
+}
+
+/// Everything `run_async_processor` needs to know, as given on the command line.
+pub struct ProcessorConfig<'a> {
+    pub input_brokers: &'a str,
+    pub output_brokers: &'a str,
+    pub group_id: &'a str,
+    pub input_topics: InputTopics<'a>,
+    pub start: StartPosition,
+    pub partitions: Option<Vec<i32>>,
+    pub checkpoint_file: Option<&'a str>,
+    pub exit_on_eof: bool,
+    pub max_messages: Option<u64>,
+    /// Milliseconds since the epoch
+    pub until_timestamp: Option<i64>,
+    pub routes: Vec<Route<'a>>,
+    pub envelope: bool,
+    pub tombstones: TombstonePolicy,
+    pub keep_input_timestamp: bool,
+    pub timestamp_expression: Option<&'a str>,
+    pub partitioner: Partitioner,
+    pub error_policy: ErrorPolicy<'a>,
+    pub delivery_policy: DeliveryPolicy,
+    pub commit_interval: Duration,
+    /// librdkafka properties overriding the defaults, in the order they should be set
+    pub consumer_properties: Vec<(String, String)>,
+    pub producer_properties: Vec<(String, String)>,
+    pub ordering: OrderingMode,
+    pub max_in_flight: usize,
+    pub max_in_flight_bytes: usize,
+    /// Messages per second
+    pub max_rate: Option<f64>,
+    /// Payload bytes per second
+    pub max_rate_bytes: Option<f64>,
+    pub rate_limit_key: Option<&'a str>,
+    /// Messages per second and rate limiting key
+    pub max_rate_per_key: Option<f64>,
+    pub sampling: Sampling<'a>,
+    pub shutdown_timeout: Duration,
+    pub parallelism: usize,
+}
+
+pub fn string_to_error_action(string: &str) -> Option<ErrorAction> {
+    match string {
+        "skip" | "drop" => Some(ErrorAction::Skip),
+        "dead-letter" => Some(ErrorAction::DeadLetter),
+        "stop" => Some(ErrorAction::Stop),
+        _ => None,
+    }
+}
+
+pub fn string_to_ordering_mode(string: &str) -> Option<OrderingMode> {
+    match string {
+        "none" => Some(OrderingMode::Unordered),
+        "partition" => Some(OrderingMode::Partition),
+        "key" => Some(OrderingMode::Key),
+        _ => None,
+    }
+}
+
+pub fn string_to_partitioner(string: &str) -> Option<Partitioner> {
+    match string {
+        "default" | "consistent_random" => Some(Partitioner::Default),
+        "murmur2" => Some(Partitioner::Murmur2),
+        "keep-input" => Some(Partitioner::KeepInput),
+        "jq" => Some(Partitioner::Jq),
+        _ => None,
+    }
+}
+
+pub fn string_to_tombstone_policy(string: &str) -> Option<TombstonePolicy> {
+    match string {
+        "skip" => Some(TombstonePolicy::Skip),
+        "pass-through" => Some(TombstonePolicy::PassThrough),
+        "null" => Some(TombstonePolicy::Null),
+        _ => None,
+    }
+}
+
 pub fn string_to_serialization_type(string: &str) -> Option<SerializationType> {
     match string {
         "JSON" => Some(SerializationType::JSON),
@@ -37,23 +283,382 @@ pub fn mk_topic_serialization<'a, 'b>(topic_string: &'a str) -> Option<TopicMeta
 where
     'a: 'b,
 {
-    let split_string: Vec<&'a str> = topic_string.split(':').collect();
-    let length: usize = split_string.len();
-    if length == 1 {
-        Some(TopicMetadata {
-            name: split_string[0],
+    // Patterns may contain ':' themselves, so only a serialization after the last one counts.
+    let mut split_string = topic_string.rsplitn(2, ':');
+    let (last, rest) = (split_string.next().unwrap(), split_string.next());
+    match (rest, string_to_serialization_type(last)) {
+        (Some(name), Some(serialization)) => Some(TopicMetadata {
+            name: name,
+            serialization: serialization,
+        }),
+        // topic names cannot contain ':', unlike patterns
+        (Some(_), None) if !topic_string.starts_with('^') => None,
+        _ => Some(TopicMetadata {
+            name: topic_string,
             serialization: SerializationType::JSON,
-        })
-    } else if length == 2 {
-        string_to_serialization_type(split_string[1]).map(|s| TopicMetadata {
-            name: split_string[0],
-            serialization: s,
-        })
+        }),
+    }
+}
+
+// The sink is dynamic as soon as jq may pick the topic, either through the envelope or through
+// an output topic expression. The output topic then only provides the default topic and the
+// serialization of every routed record.
+pub fn mk_sink_metadata<'a>(
+    output_topic: Option<&'a str>,
+    matches: &'a ArgMatches<'a>,
+) -> SinkMetadata<'a> {
+    let output_topic = output_topic.and_then(|topic| mk_topic_serialization(topic));
+    let topic_expression = matches.value_of("output-topic-expression");
+    let dynamic = matches.is_present("output-envelope") || topic_expression.is_some();
+    if !dynamic {
+        return output_topic
+            .map(|topic| SinkMetadata::SinkTopic { metadata: topic })
+            .unwrap_or(SinkMetadata::StdOut);
+    }
+    let (default_topic, serialization) = match output_topic {
+        Some(TopicMetadata {
+            name,
+            serialization,
+        }) => (Some(name), serialization),
+        None => (None, SerializationType::JSON),
+    };
+    SinkMetadata::DynamicTopic {
+        serialization: serialization,
+        routing: TopicRouting {
+            default_topic: default_topic,
+            topic_expression: topic_expression,
+            allowed_topics: matches
+                .value_of("allowed-topics")
+                .map(|topics| topics.split(',').collect()),
+            fallback_topic: matches.value_of("fallback-topic"),
+        },
+    }
+}
+
+// Dead-lettering needs somewhere to write to, so it is only accepted with `--dead-letter-topic`.
+// Once a dead-letter topic is given, every failure goes there unless told otherwise.
+pub fn mk_error_policy<'a>(matches: &'a ArgMatches<'a>) -> Option<ErrorPolicy<'a>> {
+    let dead_letter_topic = matches.value_of("dead-letter-topic");
+    let default_action = if dead_letter_topic.is_some() {
+        ErrorAction::DeadLetter
     } else {
-        None
+        ErrorAction::Skip
+    };
+    let action = |name: &str| match matches.value_of(name) {
+        None => Some(default_action),
+        Some(action) => string_to_error_action(action).and_then(|action| {
+            if action == ErrorAction::DeadLetter && dead_letter_topic.is_none() {
+                None
+            } else {
+                Some(action)
+            }
+        }),
+    };
+    Some(ErrorPolicy {
+        on_decode_error: action("on-decode-error")?,
+        on_jq_error: action("on-jq-error")?,
+        on_serialization_error: action("on-serialization-error")?,
+        on_routing_error: action("on-routing-error")?,
+        on_delivery_error: action("on-delivery-error")?,
+        dead_letter_topic: dead_letter_topic,
+    })
+}
+
+// Parses a route of the form `<jq expression> => <topic>[:<serialization>]`. jq has no `=>`
+// operator, so the last one separates the program from its sink.
+pub fn mk_route<'a>(route_string: &'a str, matches: &'a ArgMatches<'a>) -> Option<Route<'a>> {
+    let index = route_string.rfind("=>")?;
+    let topic = route_string[index + 2..].trim();
+    // reject unknown serializations instead of silently printing to stdout
+    mk_topic_serialization(topic)?;
+    Some(Route {
+        jq_expression: route_string[..index].trim(),
+        sink: mk_sink_metadata(Some(topic), matches),
+    })
+}
+
+// Without any `--route`, `--jq-expression` and `--output-topic` make up the only route.
+pub fn mk_routes<'a>(matches: &'a ArgMatches<'a>) -> Option<Vec<Route<'a>>> {
+    match matches.values_of("route") {
+        Some(route_strings) => route_strings
+            .map(|route_string| mk_route(route_string, matches))
+            .collect(),
+        None => Some(vec![
+            Route {
+                jq_expression: matches.value_of("jq_expression").unwrap(),
+                sink: mk_sink_metadata(matches.value_of("output-topic"), matches),
+            },
+        ]),
+    }
+}
+
+/// Parses milliseconds since the epoch, or an ISO-8601 date such as `2018-03-01T12:00:00Z`. Dates
+/// without an offset, like `2018-03-01T12:00:00` or `2018-03-01`, are taken as UTC.
+pub fn parse_timestamp(string: &str) -> Option<i64> {
+    if let Ok(millis) = string.parse::<i64>() {
+        return Some(millis);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(string) {
+        return Some(date.timestamp_millis());
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(string, "%Y-%m-%dT%H:%M:%S%.f") {
+        return Some(date.and_utc().timestamp_millis());
+    }
+    NaiveDate::parse_from_str(string, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date| date.and_utc().timestamp_millis())
+}
+
+pub fn mk_input_topics<'a>(matches: &'a ArgMatches<'a>) -> Result<InputTopics<'a>, String> {
+    let mut topics = Vec::new();
+    let mut patterns = Vec::new();
+    for topic_string in matches.values_of("input-topic").unwrap() {
+        let topic = mk_topic_serialization(topic_string)
+            .ok_or("Invalid input topic, expected <topic>[:<serialization>]")?;
+        if topic.name.starts_with('^') {
+            let pattern = Regex::new(topic.name)
+                .map_err(|err| format!("Invalid input topic pattern {}: {}", topic.name, err))?;
+            patterns.push((pattern, topics.len()));
+        }
+        topics.push(topic);
+    }
+    Ok(InputTopics {
+        topics: topics,
+        patterns: patterns,
+    })
+}
+
+pub fn mk_start_position(matches: &ArgMatches) -> Result<StartPosition, String> {
+    if matches.is_present("from-beginning") {
+        Ok(StartPosition::Beginning)
+    } else if matches.is_present("from-offset") {
+        parse_arg(matches, "from-offset").map(StartPosition::Offset)
+    } else if let Some(timestamp) = matches.value_of("from-timestamp") {
+        parse_timestamp(timestamp)
+            .map(StartPosition::Timestamp)
+            .ok_or(format!("Invalid value for --from-timestamp: {}", timestamp))
+    } else {
+        Ok(StartPosition::Committed)
+    }
+}
+
+pub fn mk_partitions(matches: &ArgMatches) -> Result<Option<Vec<i32>>, String> {
+    match matches.value_of("partitions") {
+        None => Ok(None),
+        Some(partitions) => partitions
+            .split(',')
+            .map(|partition| {
+                partition
+                    .trim()
+                    .parse::<i32>()
+                    .map_err(|_| format!("Invalid partition in --partitions: {}", partition))
+            })
+            .collect::<Result<Vec<i32>, String>>()
+            .map(Some),
+    }
+}
+
+fn read_properties_file(path: &str) -> Result<Vec<(String, String)>, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| format!("Could not read {}: {}", path, err))?;
+    parse_properties(&contents)
+        .map(from_java_properties)
+        .map_err(|err| format!("{} in {}", err, path))
+}
+
+fn property_values(matches: &ArgMatches, name: &str) -> Result<Vec<(String, String)>, String> {
+    matches
+        .values_of(name)
+        .map(|values| values.collect::<Vec<&str>>())
+        .unwrap_or_else(Vec::new)
+        .into_iter()
+        .map(|property| {
+            parse_property(property)
+                .ok_or(format!("Invalid property, expected key=value: {}", property))
+        })
+        .collect()
+}
+
+fn properties_file(matches: &ArgMatches, name: &str) -> Result<Vec<(String, String)>, String> {
+    match matches.value_of(name) {
+        Some(path) => read_properties_file(path),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Files come before flags, and settings shared by both clients before the client specific ones,
+// so that the most specific setting wins.
+pub fn mk_client_properties(
+    matches: &ArgMatches,
+) -> Result<(Vec<(String, String)>, Vec<(String, String)>), String> {
+    let client_properties = |config_file: &str,
+                             property: &str|
+     -> Result<Vec<(String, String)>, String> {
+        let mut properties = properties_file(matches, "config-file")?;
+        properties.extend(properties_file(matches, config_file)?);
+        properties.extend(property_values(matches, "property")?);
+        properties.extend(property_values(matches, property)?);
+        Ok(properties)
+    };
+    Ok((
+        client_properties("consumer-config-file", "consumer-property")?,
+        client_properties("producer-config-file", "producer-property")?,
+    ))
+}
+
+fn parse_ratio(name: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(ratio),
+        _ => Err(format!("Invalid value for --{}: {}", name, value)),
     }
 }
 
+pub fn mk_sampling<'a>(matches: &'a ArgMatches<'a>) -> Result<Sampling<'a>, String> {
+    if let Some(ratio) = matches.value_of("sample") {
+        return parse_ratio("sample", ratio).map(Sampling::Random);
+    }
+    match matches.values_of("sample-by-key") {
+        Some(mut values) => {
+            let expression = values.next().unwrap();
+            if !jq_check(expression, &[]) {
+                return Err(format!("Could not compile --sample-by-key: {}", expression));
+            }
+            let ratio = parse_ratio("sample-by-key", values.next().unwrap())?;
+            Ok(Sampling::ByKey(expression, ratio))
+        }
+        None => Ok(Sampling::All),
+    }
+}
+
+fn parse_rate(matches: &ArgMatches, name: &str) -> Result<Option<f64>, String> {
+    match matches.value_of(name) {
+        None => Ok(None),
+        Some(value) => match value.parse::<f64>() {
+            Ok(rate) if rate > 0.0 => Ok(Some(rate)),
+            _ => Err(format!("Invalid value for --{}: {}", name, value)),
+        },
+    }
+}
+
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<T, String> {
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value for --{}: {}", name, value))
+}
+
+// A limit of 0 would never let anything through, an interval of 0 would busy loop, and a pool of 0
+// threads would never run anything.
+fn parse_limit(matches: &ArgMatches, name: &str) -> Result<usize, String> {
+    match parse_arg::<usize>(matches, name)? {
+        0 => Err(format!("Invalid value for --{}: must be greater than 0", name)),
+        limit => Ok(limit),
+    }
+}
+
+// The workers compile their own copy of each expression, which must not fail there.
+fn check_expression(matches: &ArgMatches, name: &str) -> Result<(), String> {
+    match matches.value_of(name) {
+        Some(expression) if !jq_check(expression, &[]) => {
+            Err(format!("Could not compile --{}: {}", name, expression))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn mk_processor_config<'a>(matches: &'a ArgMatches<'a>) -> Result<ProcessorConfig<'a>, String> {
+    let input_topics = mk_input_topics(matches)?;
+    let routes = mk_routes(matches)
+        .ok_or("Invalid route, expected '<jq expression> => <topic>[:<serialization>]'")?;
+    if let Some(route) = routes
+        .iter()
+        .find(|route| !jq_check(route.jq_expression, &["topic", "key"]))
+    {
+        return Err(format!("Could not compile the jq program {}", route.jq_expression));
+    }
+    check_expression(matches, "output-topic-expression")?;
+    check_expression(matches, "output-timestamp-expression")?;
+    check_expression(matches, "rate-limit-key")?;
+    let error_policy = mk_error_policy(matches)
+        .ok_or("The 'dead-letter' error action requires --dead-letter-topic")?;
+    let (consumer_properties, producer_properties) = mk_client_properties(matches)?;
+    let start = mk_start_position(matches)?;
+    let partitioner = string_to_partitioner(matches.value_of("partitioner").unwrap()).unwrap();
+    // Only the output envelope sets keys and partitions, murmur2 would quietly be random without
+    if (partitioner == Partitioner::Jq || partitioner == Partitioner::Murmur2)
+        && !matches.is_present("output-envelope")
+    {
+        let name = matches.value_of("partitioner").unwrap();
+        return Err(format!("--partitioner {} requires --output-envelope", name));
+    }
+    let partitions = mk_partitions(matches)?;
+    let checkpoint_file = matches.value_of("checkpoint-file");
+    if input_topics.has_patterns() && checkpoint_file.is_some() {
+        return Err("Topic patterns cannot be combined with a checkpoint file".into());
+    }
+    if input_topics.has_patterns() && (start != StartPosition::Committed || partitions.is_some()) {
+        return Err("Topic patterns cannot be combined with a start position or partitions".into());
+    }
+    // Partitions missing from the checkpoint have no committed offset to start from either.
+    let start = match (start, checkpoint_file) {
+        (StartPosition::Committed, Some(_)) => StartPosition::Beginning,
+        (start, _) => start,
+    };
+    Ok(ProcessorConfig {
+        input_brokers: matches
+            .value_of("input-brokers")
+            .unwrap_or(matches.value_of("brokers").unwrap()),
+        output_brokers: matches
+            .value_of("output-brokers")
+            .unwrap_or(matches.value_of("brokers").unwrap()),
+        group_id: matches.value_of("group-id").unwrap(),
+        input_topics: input_topics,
+        start: start,
+        partitions: partitions,
+        checkpoint_file: checkpoint_file,
+        exit_on_eof: matches.is_present("exit-on-eof"),
+        max_messages: match matches.value_of("max-messages") {
+            Some(_) => Some(parse_arg(matches, "max-messages")?),
+            None => None,
+        },
+        until_timestamp: match matches.value_of("until-timestamp") {
+            Some(timestamp) => Some(
+                parse_timestamp(timestamp)
+                    .ok_or(format!("Invalid value for --until-timestamp: {}", timestamp))?,
+            ),
+            None => None,
+        },
+        routes: routes,
+        envelope: matches.is_present("output-envelope"),
+        partitioner: partitioner,
+        tombstones: string_to_tombstone_policy(matches.value_of("on-tombstone").unwrap()).unwrap(),
+        keep_input_timestamp: matches.is_present("keep-input-timestamp"),
+        timestamp_expression: matches.value_of("output-timestamp-expression"),
+        error_policy: error_policy,
+        delivery_policy: DeliveryPolicy {
+            max_attempts: parse_arg(matches, "max-produce-attempts")?,
+            backoff: Duration::from_millis(parse_arg(matches, "produce-backoff")?),
+            max_backoff: Duration::from_millis(parse_arg(matches, "max-produce-backoff")?),
+        },
+        commit_interval: Duration::from_millis(parse_limit(matches, "commit-interval")? as u64),
+        consumer_properties: consumer_properties,
+        producer_properties: producer_properties,
+        ordering: string_to_ordering_mode(matches.value_of("ordering").unwrap()).unwrap(),
+        max_in_flight: parse_limit(matches, "max-in-flight")?,
+        max_in_flight_bytes: parse_limit(matches, "max-in-flight-bytes")?,
+        max_rate: parse_rate(matches, "max-rate")?,
+        max_rate_bytes: parse_rate(matches, "max-rate-bytes")?,
+        rate_limit_key: matches.value_of("rate-limit-key"),
+        max_rate_per_key: parse_rate(matches, "max-rate-per-key")?,
+        sampling: mk_sampling(matches)?,
+        shutdown_timeout: Duration::from_millis(parse_arg(matches, "shutdown-timeout")?),
+        parallelism: parse_limit(matches, "parallelism")?,
+    })
+}
+
 pub fn mk_cli_matches<'a, 'b>() -> App<'a, 'b>
 where
     'a: 'b,
@@ -77,6 +682,18 @@ where
                 .takes_value(true)
                 .default_value("localhost:9092"),
         )
+        .arg(
+            Arg::with_name("input-brokers")
+                .long("input-brokers")
+                .help("Broker list of the input cluster [default: --brokers]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-brokers")
+                .long("output-brokers")
+                .help("Broker list of the output cluster [default: --brokers]")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("group-id")
                 .short("g")
@@ -85,6 +702,48 @@ where
                 .takes_value(true)
                 .default_value("example_consumer_group_id"),
         )
+        .arg(
+            Arg::with_name("property")
+                .short("X")
+                .help("librdkafka property for both the consumer and the producer, as key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("consumer-property")
+                .long("consumer-property")
+                .help("librdkafka property for the consumer only, as key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("producer-property")
+                .long("producer-property")
+                .help("librdkafka property for the producer only, as key=value")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("config-file")
+                .long("config-file")
+                .help("Properties file, as used by the Java clients, for both clients")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("consumer-config-file")
+                .long("consumer-config-file")
+                .help("Properties file for the consumer only")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("producer-config-file")
+                .long("producer-config-file")
+                .help("Properties file for the producer only")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("log-conf")
                 .long("log-conf")
@@ -94,10 +753,72 @@ where
         .arg(
             Arg::with_name("input-topic")
                 .long("input-topic")
-                .help("Input topic")
+                .help(
+                    "Input topic, as <topic>[:<serialization>]. Repeat it to consume several \
+                     topics, or start the name with ^ to subscribe to a pattern",
+                )
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("from-beginning")
+                .long("from-beginning")
+                .help("Start from the beginning of the input partitions")
+                .conflicts_with_all(&["from-offset", "from-timestamp"]),
+        )
+        .arg(
+            Arg::with_name("from-offset")
+                .long("from-offset")
+                .help("Start every input partition at this offset")
+                .takes_value(true)
+                .conflicts_with("from-timestamp"),
+        )
+        .arg(
+            Arg::with_name("from-timestamp")
+                .long("from-timestamp")
+                .help(
+                    "Start at the first message produced at or after this time, in milliseconds \
+                     since the epoch or as an ISO-8601 date",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("partitions")
+                .long("partitions")
+                .help("Only consume these input partitions, e.g. 0,3,5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checkpoint-file")
+                .long("checkpoint-file")
+                .help(
+                    "Assign the input partitions and store their offsets in this file instead of \
+                     the consumer group, resuming from it on restart",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exit-on-eof")
+                .long("exit-on-eof")
+                .help("Stop once every input partition has been read to its end"),
+        )
+        .arg(
+            Arg::with_name("max-messages")
+                .long("max-messages")
+                .help("Stop after processing this many messages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until-timestamp")
+                .long("until-timestamp")
+                .help(
+                    "Skip the messages produced at or after this time, and stop once every \
+                     input partition got there or to its end",
+                )
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("output-topic")
                 .long("output-topic")
@@ -105,6 +826,253 @@ where
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("route")
+                .long("route")
+                .help(
+                    "A '<jq expression> => <topic>[:<serialization>]' route; can be repeated. \
+                     Overrides --jq-expression and --output-topic",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("output-envelope")
+                .long("output-envelope")
+                .help(
+                    "Treat each jq result as an object with a 'value' and optional 'key' and \
+                     'topic' fields",
+                ),
+        )
+        .arg(
+            Arg::with_name("output-topic-expression")
+                .long("output-topic-expression")
+                .help("A jq expression evaluated on each result to pick its output topic")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allowed-topics")
+                .long("allowed-topics")
+                .help("Comma-separated list of topics jq is allowed to route to")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fallback-topic")
+                .long("fallback-topic")
+                .help("Topic for results that jq routed to a topic that is not allowed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("partitioner")
+                .long("partitioner")
+                .help(
+                    "How to pick the output partition: librdkafka's default \
+                     (consistent_random), murmur2 of the key like the Java clients, the input \
+                     partition, or the partition field of the output envelope",
+                )
+                .takes_value(true)
+                .possible_values(&[
+                    "default",
+                    "consistent_random",
+                    "murmur2",
+                    "keep-input",
+                    "jq",
+                ])
+                .default_value("default"),
+        )
+        .arg(
+            Arg::with_name("keep-input-timestamp")
+                .long("keep-input-timestamp")
+                .help("Give each result the timestamp of its input message"),
+        )
+        .arg(
+            Arg::with_name("output-timestamp-expression")
+                .long("output-timestamp-expression")
+                .help(
+                    "A jq expression evaluated on each result to pick its timestamp, in \
+                     milliseconds since the epoch or as an ISO-8601 date",
+                )
+                .takes_value(true)
+                .conflicts_with("keep-input-timestamp"),
+        )
+        .arg(
+            Arg::with_name("on-tombstone")
+                .long("on-tombstone")
+                .help(
+                    "What to do with input messages without a payload: skip them, produce \
+                     a tombstone with the same key, or run jq on null",
+                )
+                .takes_value(true)
+                .possible_values(&["skip", "pass-through", "null"])
+                .default_value("skip"),
+        )
+        .arg(
+            Arg::with_name("on-decode-error")
+                .long("on-decode-error")
+                .help(
+                    "What to do with messages that cannot be decoded \
+                     [default: dead-letter with --dead-letter-topic, skip otherwise]",
+                )
+                .takes_value(true)
+                .possible_values(&["skip", "dead-letter", "stop"]),
+        )
+        .arg(
+            Arg::with_name("on-jq-error")
+                .long("on-jq-error")
+                .help(
+                    "What to do with messages for which the jq program raises an error \
+                     [default: dead-letter with --dead-letter-topic, skip otherwise]",
+                )
+                .takes_value(true)
+                .possible_values(&["skip", "dead-letter", "stop"]),
+        )
+        .arg(
+            Arg::with_name("on-serialization-error")
+                .long("on-serialization-error")
+                .help(
+                    "What to do with messages whose jq results cannot be serialized \
+                     [default: dead-letter with --dead-letter-topic, skip otherwise]",
+                )
+                .takes_value(true)
+                .possible_values(&["skip", "dead-letter", "stop"]),
+        )
+        .arg(
+            Arg::with_name("on-routing-error")
+                .long("on-routing-error")
+                .help(
+                    "What to do with messages that have a jq result with no topic to go to \
+                     [default: dead-letter with --dead-letter-topic, skip otherwise]",
+                )
+                .takes_value(true)
+                .possible_values(&["skip", "dead-letter", "stop"]),
+        )
+        .arg(
+            Arg::with_name("on-delivery-error")
+                .long("on-delivery-error")
+                .help(
+                    "What to do with messages whose results could not be produced after all \
+                     attempts [default: dead-letter with --dead-letter-topic, drop otherwise]",
+                )
+                .takes_value(true)
+                .possible_values(&["drop", "dead-letter", "stop"]),
+        )
+        .arg(
+            Arg::with_name("max-produce-attempts")
+                .long("max-produce-attempts")
+                .help("How many times to try producing a result that fails with a retriable error")
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("produce-backoff")
+                .long("produce-backoff-ms")
+                .help("Delay before the first retry, doubled after each attempt")
+                .takes_value(true)
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("max-produce-backoff")
+                .long("max-produce-backoff-ms")
+                .help("Longest delay between two attempts")
+                .takes_value(true)
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::with_name("dead-letter-topic")
+                .long("dead-letter-topic")
+                .help("Topic that receives the original key and value of every failed message")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("commit-interval")
+                .long("commit-interval-ms")
+                .help("How often to commit the offsets of fully delivered messages")
+                .takes_value(true)
+                .default_value("5000"),
+        )
+        .arg(
+            Arg::with_name("ordering")
+                .long("ordering")
+                .help(
+                    "Produce the results of messages from the same input partition, or with \
+                     the same key, in the order they were consumed",
+                )
+                .takes_value(true)
+                .possible_values(&["none", "partition", "key"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("max-in-flight")
+                .long("max-in-flight")
+                .help("Stop consuming while this many messages are being processed or produced")
+                .takes_value(true)
+                .default_value("1000"),
+        )
+        .arg(
+            Arg::with_name("max-in-flight-bytes")
+                .long("max-in-flight-bytes")
+                .help("Stop consuming while the messages being processed weigh this many bytes")
+                .takes_value(true)
+                .default_value("67108864"),
+        )
+        .arg(
+            Arg::with_name("max-rate")
+                .long("max-rate")
+                .help("Consume at most this many messages per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-rate-bytes")
+                .long("max-rate-bytes")
+                .help("Consume at most this many payload bytes per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-key")
+                .long("rate-limit-key")
+                .help(
+                    "A jq expression evaluated on each input message to pick its rate limiting \
+                     key",
+                )
+                .takes_value(true)
+                .requires("max-rate-per-key"),
+        )
+        .arg(
+            Arg::with_name("max-rate-per-key")
+                .long("max-rate-per-key")
+                .help(
+                    "Process at most this many messages per second with the same rate limiting \
+                     key",
+                )
+                .takes_value(true)
+                .requires("rate-limit-key"),
+        )
+        .arg(
+            Arg::with_name("shutdown-timeout")
+                .long("shutdown-timeout-ms")
+                .help("How long to drain, flush and commit after SIGINT or SIGTERM")
+                .takes_value(true)
+                .default_value("30000"),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .help("Only process this fraction of the input messages, picked at random")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample-by-key")
+                .long("sample-by-key")
+                .help(
+                    "Only process the input messages whose key, picked by a jq expression, falls \
+                     in this fraction of the keys, e.g. '.user_id' 0.05",
+                )
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["expression", "ratio"])
+                .conflicts_with("sample"),
+        )
         .arg(
             Arg::with_name("parallelism")
                 .short("p")
@@ -114,3 +1082,121 @@ where
                 .default_value("4"),
         )
 }
+
+#[cfg(test)]
+mod tests {
+
+    use cli::Partitioner;
+    use cli::SerializationType;
+    use cli::string_to_partitioner;
+    use cli::{mk_cli_matches, mk_input_topics, mk_routes, mk_topic_serialization};
+    use cli::parse_limit;
+    use cli::parse_timestamp;
+
+    fn is_bson(serialization: &SerializationType) -> bool {
+        match *serialization {
+            SerializationType::BSON => true,
+            SerializationType::JSON => false,
+        }
+    }
+
+    #[test]
+    fn it_splits_the_serialization_off_the_topic() {
+        let topic = mk_topic_serialization("orders:BSON").unwrap();
+        assert_eq!(topic.name, "orders");
+        assert!(is_bson(&topic.serialization));
+        let topic = mk_topic_serialization("orders").unwrap();
+        assert_eq!(topic.name, "orders");
+        assert!(!is_bson(&topic.serialization));
+        assert!(mk_topic_serialization("orders:XML").is_none());
+    }
+
+    #[test]
+    fn it_keeps_the_colons_of_a_pattern() {
+        let matches = mk_cli_matches().get_matches_from(vec![
+            "kafka-jq",
+            "--input-topic",
+            "^pat:tern:BSON",
+            "--input-topic",
+            "^pat:tern",
+        ]);
+        let input_topics = mk_input_topics(&matches).unwrap();
+        assert_eq!(input_topics.names(), vec!["^pat:tern", "^pat:tern"]);
+        assert!(is_bson(&input_topics.topics[0].serialization));
+        assert!(!is_bson(&input_topics.topics[1].serialization));
+    }
+
+    #[test]
+    fn it_parses_routes() {
+        let matches = mk_cli_matches().get_matches_from(vec![
+            "kafka-jq",
+            "--input-topic",
+            "input",
+            "--route",
+            r#"select(.at == "12:00") => noon:BSON"#,
+            "--route",
+            ". => all",
+        ]);
+        let routes = mk_routes(&matches).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].jq_expression, r#"select(.at == "12:00")"#);
+        assert!(is_bson(routes[0].sink.serialization()));
+        assert_eq!(routes[1].jq_expression, ".");
+        assert!(!is_bson(routes[1].sink.serialization()));
+    }
+
+    #[test]
+    fn it_rejects_invalid_routes() {
+        for route in &[". -> out", ". => out:XML"] {
+            let matches = mk_cli_matches().get_matches_from(vec![
+                "kafka-jq",
+                "--input-topic",
+                "input",
+                "--route",
+                *route,
+            ]);
+            assert!(mk_routes(&matches).is_none(), "accepted {}", route);
+        }
+    }
+
+    #[test]
+    fn it_accepts_consistent_random_as_the_default_partitioner() {
+        let matches = mk_cli_matches().get_matches_from(vec![
+            "kafka-jq",
+            "--input-topic",
+            "input",
+            "--partitioner",
+            "consistent_random",
+        ]);
+        assert_eq!(
+            string_to_partitioner(matches.value_of("partitioner").unwrap()),
+            Some(Partitioner::Default)
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_limit_of_zero() {
+        let matches = mk_cli_matches().get_matches_from(vec![
+            "kafka-jq",
+            "--input-topic",
+            "input",
+            "--max-in-flight",
+            "0",
+            "--max-in-flight-bytes",
+            "1",
+        ]);
+        assert!(parse_limit(&matches, "max-in-flight").is_err());
+        assert_eq!(parse_limit(&matches, "max-in-flight-bytes"), Ok(1));
+    }
+
+    #[test]
+    fn it_parses_timestamps() {
+        assert_eq!(parse_timestamp("1519905600000"), Some(1519905600000));
+        assert_eq!(parse_timestamp("2018-03-01T12:00:00Z"), Some(1519905600000));
+        assert_eq!(parse_timestamp("2018-03-01T13:00:00+01:00"), Some(1519905600000));
+        assert_eq!(parse_timestamp("2018-03-01T12:00:00"), Some(1519905600000));
+        assert_eq!(parse_timestamp("2018-03-01T12:00:00.250"), Some(1519905600250));
+        assert_eq!(parse_timestamp("2018-03-01"), Some(1519862400000));
+        assert_eq!(parse_timestamp("March 1st"), None);
+    }
+}
@@ -0,0 +1,125 @@
+use futures::Future;
+use futures::future;
+use futures::sync::oneshot::Canceled;
+
+use rdkafka::Message;
+use rdkafka::error::KafkaError;
+use rdkafka::error::RDKafkaError;
+use rdkafka::producer::DeliveryFuture;
+use rdkafka::producer::FutureProducer;
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Timeout;
+
+use cli::DeliveryPolicy;
+
+/// How long `send_copy` may block the CPU pool while the producer queue is full. A message that
+/// still does not fit fails with `QueueFull`, which is retried like any other transient error.
+/// Sends from the event loop never block: they fail with `QueueFull` right away instead.
+pub const QUEUE_FULL_BLOCK_MS: i64 = 1000;
+
+/// The final outcome of producing one result: its partition and offset, or the last error.
+pub type Delivery = Box<Future<Item = Result<(i32, i64), KafkaError>, Error = Canceled>>;
+
+/// Whether producing the same message again may succeed.
+pub fn is_retriable(err: &KafkaError) -> bool {
+    match *err {
+        KafkaError::MessageProduction(RDKafkaError::MessageTimedOut)
+        | KafkaError::MessageProduction(RDKafkaError::QueueFull)
+        | KafkaError::MessageProduction(RDKafkaError::AllBrokersDown)
+        | KafkaError::MessageProduction(RDKafkaError::BrokerTransportFailure)
+        | KafkaError::MessageProduction(RDKafkaError::LeaderNotAvailable)
+        | KafkaError::MessageProduction(RDKafkaError::NotLeaderForPartition)
+        | KafkaError::MessageProduction(RDKafkaError::RequestTimedOut)
+        | KafkaError::MessageProduction(RDKafkaError::BrokerNotAvailable)
+        | KafkaError::MessageProduction(RDKafkaError::NetworkException)
+        | KafkaError::MessageProduction(RDKafkaError::NotEnoughReplicas)
+        | KafkaError::MessageProduction(RDKafkaError::NotEnoughReplicasAfterAppend) => true,
+        _ => false,
+    }
+}
+
+/// Waits for the delivery report of a result, and produces the result again with an exponential
+/// backoff as long as it fails with a retriable error and `policy` allows another attempt.
+/// Must run on the event loop, where the backoff timers live.
+pub fn deliver(
+    producer: FutureProducer,
+    delivery: DeliveryFuture,
+    policy: &'static DeliveryPolicy,
+    handle: Handle,
+    attempt: u32,
+) -> Delivery {
+    Box::new(delivery.and_then(move |report| -> Delivery {
+        let (err, msg) = match report {
+            Ok(position) => return Box::new(future::ok(Ok(position))),
+            Err(failure) => failure,
+        };
+        if attempt >= policy.max_attempts || !is_retriable(&err) {
+            return Box::new(future::ok(Err(err)));
+        }
+        let backoff = policy.backoff(attempt);
+        warn!(
+            "Could not produce to {} (attempt {}/{}): {}, retrying in {:?}",
+            msg.topic(),
+            attempt,
+            policy.max_attempts,
+            err,
+            backoff
+        );
+        let timeout = Timeout::new(backoff, &handle).expect("Could not create the retry timeout");
+        Box::new(timeout.map_err(|_| Canceled).and_then(move |_| {
+            // librdkafka reports -1 when it never picked a partition
+            let partition = if msg.partition() < 0 {
+                None
+            } else {
+                Some(msg.partition())
+            };
+            let delivery = producer.send_copy::<[u8], [u8]>(
+                msg.topic(),
+                partition,
+                msg.payload(),
+                msg.key(),
+                msg.timestamp().to_millis(),
+                // a full queue is one more failed attempt, the event loop must not block
+                0,
+            );
+            deliver(producer, delivery, policy, handle, attempt + 1)
+        }))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use rdkafka::error::KafkaError;
+    use rdkafka::error::RDKafkaError;
+
+    use cli::DeliveryPolicy;
+    use delivery::is_retriable;
+
+    #[test]
+    fn it_doubles_the_backoff_up_to_the_maximum() {
+        let policy = DeliveryPolicy {
+            max_attempts: 10,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(40), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn it_only_retries_transient_errors() {
+        assert!(is_retriable(&KafkaError::MessageProduction(
+            RDKafkaError::MessageTimedOut
+        )));
+        assert!(!is_retriable(&KafkaError::MessageProduction(
+            RDKafkaError::MessageSizeTooLarge
+        )));
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The stage of `jq_computation` at which a message failed. Each class has its own
+/// `ErrorAction` and its own counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The payload is missing, or is not valid BSON or JSON.
+    Decode,
+    /// The jq program raised an error, e.g. through `error("bad")`.
+    JqRuntime,
+    /// A jq result could not be written in the output serialization.
+    Serialization,
+    /// A jq result has no topic to go to: the one jq picked is not allowed and there is no
+    /// fallback topic, or jq picked none and there is no default topic.
+    Routing,
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorClass::Decode => write!(f, "decode error"),
+            ErrorClass::JqRuntime => write!(f, "jq runtime error"),
+            ErrorClass::Serialization => write!(f, "serialization error"),
+            ErrorClass::Routing => write!(f, "routing error"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProcessingError {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+impl ProcessingError {
+    pub fn decode<S: Into<String>>(message: S) -> ProcessingError {
+        ProcessingError {
+            class: ErrorClass::Decode,
+            message: message.into(),
+        }
+    }
+
+    pub fn jq_runtime<S: Into<String>>(message: S) -> ProcessingError {
+        ProcessingError {
+            class: ErrorClass::JqRuntime,
+            message: message.into(),
+        }
+    }
+
+    pub fn serialization<S: Into<String>>(message: S) -> ProcessingError {
+        ProcessingError {
+            class: ErrorClass::Serialization,
+            message: message.into(),
+        }
+    }
+
+    pub fn routing<S: Into<String>>(message: S) -> ProcessingError {
+        ProcessingError {
+            class: ErrorClass::Routing,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.class, self.message)
+    }
+}
+
+/// Failure counters, shared by all the workers of the CPU pool. Results that could not be
+/// produced are counted apart from the messages that failed processing.
+#[derive(Default)]
+pub struct ErrorCounters {
+    decode: AtomicUsize,
+    jq_runtime: AtomicUsize,
+    serialization: AtomicUsize,
+    routing: AtomicUsize,
+    delivery: AtomicUsize,
+}
+
+impl ErrorCounters {
+    fn counter(&self, class: ErrorClass) -> &AtomicUsize {
+        match class {
+            ErrorClass::Decode => &self.decode,
+            ErrorClass::JqRuntime => &self.jq_runtime,
+            ErrorClass::Serialization => &self.serialization,
+            ErrorClass::Routing => &self.routing,
+        }
+    }
+
+    /// Records one failure and returns the number of failures of that class so far.
+    pub fn record(&self, class: ErrorClass) -> usize {
+        self.counter(class).fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn count(&self, class: ErrorClass) -> usize {
+        self.counter(class).load(Ordering::Relaxed)
+    }
+
+    /// Records one result that could not be produced and returns the number of them so far.
+    pub fn record_delivery_failure(&self) -> usize {
+        self.delivery.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn delivery_failures(&self) -> usize {
+        self.delivery.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for ErrorCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "decode errors: {}, jq runtime errors: {}, serialization errors: {}, \
+             routing errors: {}, delivery failures: {}",
+            self.count(ErrorClass::Decode),
+            self.count(ErrorClass::JqRuntime),
+            self.count(ErrorClass::Serialization),
+            self.count(ErrorClass::Routing),
+            self.delivery_failures()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use error::{ErrorClass, ErrorCounters};
+
+    #[test]
+    fn it_counts_each_class_separately() {
+        let counters = ErrorCounters::default();
+
+        assert_eq!(counters.record(ErrorClass::JqRuntime), 1);
+        assert_eq!(counters.record(ErrorClass::JqRuntime), 2);
+        assert_eq!(counters.record(ErrorClass::Decode), 1);
+
+        assert_eq!(counters.count(ErrorClass::JqRuntime), 2);
+        assert_eq!(counters.count(ErrorClass::Decode), 1);
+        assert_eq!(counters.count(ErrorClass::Serialization), 0);
+        assert_eq!(counters.delivery_failures(), 0);
+    }
+}
@@ -0,0 +1,137 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+
+use futures::Future;
+use futures::future;
+use futures::unsync::oneshot;
+
+/// Counts the messages handed to the CPU pool whose results are not delivered yet, and makes
+/// the consumer wait once there are too many of them or they weigh too much. Lives on the event
+/// loop thread.
+///
+/// Parked messages wait for their rate limiting key: they still count as in flight, but leave
+/// their room to the messages of the other keys until they are unparked.
+pub struct InFlight {
+    max_messages: usize,
+    max_bytes: usize,
+    messages: Cell<usize>,
+    bytes: Cell<usize>,
+    parked: Cell<usize>,
+    parked_bytes: Cell<usize>,
+    waiting: RefCell<Option<oneshot::Sender<()>>>,
+}
+
+impl InFlight {
+    pub fn new(max_messages: usize, max_bytes: usize) -> InFlight {
+        InFlight {
+            max_messages: max_messages,
+            max_bytes: max_bytes,
+            messages: Cell::new(0),
+            bytes: Cell::new(0),
+            parked: Cell::new(0),
+            parked_bytes: Cell::new(0),
+            waiting: RefCell::new(None),
+        }
+    }
+
+    pub fn messages(&self) -> usize {
+        self.messages.get()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.messages.get() - self.parked.get() >= self.max_messages
+            || self.bytes.get() - self.parked_bytes.get() >= self.max_bytes
+    }
+
+    pub fn acquire(&self, bytes: usize) {
+        self.messages.set(self.messages.get() + 1);
+        self.bytes.set(self.bytes.get() + bytes);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.messages.set(self.messages.get() - 1);
+        self.bytes.set(self.bytes.get() - bytes);
+        self.resume();
+    }
+
+    pub fn park(&self, bytes: usize) {
+        self.parked.set(self.parked.get() + 1);
+        self.parked_bytes.set(self.parked_bytes.get() + bytes);
+        self.resume();
+    }
+
+    pub fn unpark(&self, bytes: usize) {
+        self.parked.set(self.parked.get() - 1);
+        self.parked_bytes.set(self.parked_bytes.get() - bytes);
+    }
+
+    fn resume(&self) {
+        if !self.is_full() {
+            if let Some(waiting) = self.waiting.borrow_mut().take() {
+                info!("Resuming consumption");
+                let _ = waiting.send(());
+            }
+        }
+    }
+
+    /// Resolves as soon as there is room for another message.
+    pub fn wait_for_room(&self) -> Box<Future<Item = (), Error = ()>> {
+        if !self.is_full() {
+            return Box::new(future::ok(()));
+        }
+        warn!(
+            "Pausing consumption: {} messages ({} bytes) in flight",
+            self.messages.get(),
+            self.bytes.get()
+        );
+        let (sender, receiver) = oneshot::channel();
+        *self.waiting.borrow_mut() = Some(sender);
+        Box::new(receiver.map_err(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use futures::Future;
+    use inflight::InFlight;
+
+    #[test]
+    fn it_is_full_by_count_or_bytes() {
+        let in_flight = InFlight::new(2, 100);
+        in_flight.acquire(10);
+        assert!(!in_flight.is_full());
+        in_flight.acquire(10);
+        assert!(in_flight.is_full());
+        in_flight.release(10);
+        in_flight.acquire(200);
+        assert!(in_flight.is_full());
+    }
+
+    #[test]
+    fn it_wakes_the_consumer_up_once_there_is_room() {
+        let in_flight = InFlight::new(1, 100);
+        in_flight.acquire(10);
+        let room = in_flight.wait_for_room();
+        assert!(in_flight.waiting.borrow().is_some());
+
+        in_flight.release(10);
+        assert!(in_flight.waiting.borrow().is_none());
+        assert_eq!(room.wait(), Ok(()));
+    }
+
+    #[test]
+    fn it_leaves_the_room_of_parked_messages_to_the_others() {
+        let in_flight = InFlight::new(2, 100);
+        in_flight.acquire(10);
+        in_flight.acquire(10);
+        let room = in_flight.wait_for_room();
+
+        in_flight.park(10);
+        assert_eq!(in_flight.messages(), 2);
+        assert_eq!(room.wait(), Ok(()));
+
+        in_flight.unpark(10);
+        assert!(in_flight.is_full());
+    }
+}
@@ -74,10 +74,195 @@ pub fn jv_number_value(arg: jv) -> f64 {
     unsafe { ffi::jv_number_value(arg) }
 }
 
+/// Compiles `program` into `state`, binding each of `args` to a `$name` variable, a string or
+/// `null` for `None`. Returns whether the program compiled.
+pub fn jq_compile_with_args(
+    state: *mut ffi::jq_state,
+    program: &str,
+    args: &[(&str, Option<&str>)],
+) -> bool {
+    let program = CString::new(program).unwrap();
+    unsafe {
+        let mut object = ffi::jv_object();
+        for &(name, value) in args {
+            let value = match value {
+                Some(value) => jv_string(value.to_owned()),
+                None => ffi::jv_null(),
+            };
+            object = ffi::jv_object_set(object, jv_string(name.to_owned()), value);
+        }
+        ffi::jq_compile_args(state, program.as_ptr(), object) != 0
+    }
+}
+
+/// Returns whether `program` compiles, with each of `args` bound to a `$name` variable. Lets the
+/// programs be checked once at startup, before the workers compile their own copies.
+pub fn jq_check(program: &str, args: &[&str]) -> bool {
+    let args: Vec<(&str, Option<&str>)> = args.iter().map(|&name| (name, Some(""))).collect();
+    unsafe {
+        let mut state = ffi::jq_init();
+        let compiled = jq_compile_with_args(state, program, &args);
+        ffi::jq_teardown(&mut state);
+        compiled
+    }
+}
+
+/// Whether `program` mentions the `$name` variable. Mentions in strings or comments count too,
+/// which only costs compiling the program for values it does not need.
+pub fn uses_variable(program: &str, name: &str) -> bool {
+    let variable = format!("${}", name);
+    program.match_indices(&variable).any(|(start, _)| {
+        !program[start + variable.len()..]
+            .chars()
+            .next()
+            .map_or(false, |next| next.is_alphanumeric() || next == '_')
+    })
+}
+
+/// Returns the error carried by an invalid `jv`, as produced by `jq_next` when the program
+/// raises an error, or `None` if it only marks the end of the output. This consumes `arg`.
+pub fn jv_invalid_message(arg: jv) -> Option<String> {
+    unsafe {
+        if ffi::jv_invalid_has_msg(ffi::jv_copy(arg)) == 0 {
+            ffi::jv_free(arg);
+            return None;
+        }
+        let msg = ffi::jv_invalid_get_msg(arg);
+        let message = if jv_get_kind(msg) == ffi::jv_kind::JV_KIND_STRING {
+            jv_string_value(&msg).to_owned()
+        } else {
+            // `error({...})` and friends carry arbitrary values
+            let dumped = ffi::jv_dump_string(ffi::jv_copy(msg), 0);
+            let message = jv_string_value(&dumped).to_owned();
+            ffi::jv_free(dumped);
+            message
+        };
+        ffi::jv_free(msg);
+        Some(message)
+    }
+}
+
+/// Returns the exit code and the optional message of a program that called `halt` or
+/// `halt_error`, mirroring what the jq command line does with them.
+pub fn jq_halt_status(state: *mut ffi::jq_state) -> Option<(i32, Option<String>)> {
+    unsafe {
+        if ffi::jq_halted(state) == 0 {
+            return None;
+        }
+        let exit_code = ffi::jq_get_exit_code(state);
+        let code = match jv_get_kind(exit_code) {
+            // a plain `halt`
+            ffi::jv_kind::JV_KIND_INVALID => 0,
+            ffi::jv_kind::JV_KIND_NUMBER => jv_number_value(exit_code) as i32,
+            _ => 5,
+        };
+        ffi::jv_free(exit_code);
+        let error_message = ffi::jq_get_error_message(state);
+        let message = match jv_get_kind(error_message) {
+            ffi::jv_kind::JV_KIND_INVALID => None,
+            ffi::jv_kind::JV_KIND_STRING => Some(jv_string_value(&error_message).to_owned()),
+            _ => {
+                let dumped = ffi::jv_dump_string(ffi::jv_copy(error_message), 0);
+                let message = jv_string_value(&dumped).to_owned();
+                ffi::jv_free(dumped);
+                Some(message)
+            }
+        };
+        ffi::jv_free(error_message);
+        Some((code, message))
+    }
+}
+
+/// Helpers shared by the tests of the modules running jq programs.
+#[cfg(test)]
+pub mod testing {
+
+    use std::ffi::CString;
+
+    use jq::ffi::{jq_init, jq_state, jv, jv_parse};
+    use jq::jq_compile_with_args;
+
+    pub fn parse(json: &str) -> jv {
+        let json = CString::new(json).unwrap();
+        unsafe { jv_parse(json.as_ptr()) }
+    }
+
+    pub fn compile(program: &str) -> *mut jq_state {
+        let state = unsafe { jq_init() };
+        assert!(jq_compile_with_args(state, program, &[]));
+        state
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::ffi::CString;
+
+    use jq::ffi;
+    use jq::jq_compile_with_args;
+    use jq::jq_halt_status;
+    use jq::jv_get_kind;
     use jq::jv_string;
+    use jq::testing::parse;
+    use jq::uses_variable;
+
+    // Runs `program` on the JSON `input` to the end, then returns how it halted.
+    fn halt_status(program: &str, input: &str) -> Option<(i32, Option<String>)> {
+        let input = CString::new(input).unwrap();
+        unsafe {
+            let mut state = ffi::jq_init();
+            assert!(jq_compile_with_args(state, program, &[]));
+            ffi::jq_start(state, ffi::jv_parse(input.as_ptr()), 0);
+            loop {
+                let output = ffi::jq_next(state);
+                let done = jv_get_kind(output) == ffi::jv_kind::JV_KIND_INVALID;
+                ffi::jv_free(output);
+                if done {
+                    break;
+                }
+            }
+            let status = jq_halt_status(state);
+            ffi::jq_teardown(&mut state);
+            status
+        }
+    }
+
+    #[test]
+    fn it_reports_a_halt() {
+        assert_eq!(halt_status("halt", "null"), Some((0, None)));
+    }
+
+    #[test]
+    fn it_reports_a_halt_error() {
+        assert_eq!(halt_status("halt_error", "\"x\""), Some((5, Some("x".to_owned()))));
+        assert_eq!(halt_status("\"x\" | halt_error(3)", "null"), Some((3, Some("x".to_owned()))));
+    }
+
+    #[test]
+    fn it_reports_no_halt_for_a_program_that_ends() {
+        assert_eq!(halt_status(".", "null"), None);
+    }
+
+    #[test]
+    fn it_binds_variables() {
+        let mut state = unsafe { ffi::jq_init() };
+        let args = [("topic", Some("orders")), ("key", None)];
+        assert!(jq_compile_with_args(state, "[$topic, $key, .]", &args));
+        unsafe {
+            ffi::jq_start(state, parse("1"), 0);
+            assert!(ffi::jq_next(state) == parse("[\"orders\", null, 1]"));
+            ffi::jq_teardown(&mut state);
+        }
+    }
+
+    #[test]
+    fn it_finds_the_variables_a_program_uses() {
+        assert!(uses_variable("{key: $key, value: .}", "key"));
+        assert!(uses_variable("[.[] | $key]", "key"));
+        assert!(!uses_variable("$keys | .", "key"));
+        assert!(!uses_variable(".key", "key"));
+    }
 
     proptest! {
         #[test]
@@ -8,6 +8,7 @@ extern crate bson as extbson;
 #[allow(dead_code)]
 extern crate futures;
 extern crate futures_cpupool;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate rdkafka;
@@ -15,280 +16,1672 @@ extern crate tokio_core;
 
 pub mod logging_utils;
 pub mod cli;
+mod assignment;
 mod jq;
 mod bson;
+mod bounds;
+mod checkpoint;
+mod delivery;
+mod error;
+mod inflight;
+mod murmur2;
+mod offsets;
+mod ordering;
+mod output;
+mod properties;
+mod prune;
+mod ratelimit;
+mod rebalance;
+mod sampling;
+mod shutdown;
 
 use futures::Future;
+use futures::future;
 use futures::future::join_all;
-use futures::stream::Stream;
 use futures::sync::oneshot::Canceled;
+use futures::stream::Stream;
 use futures_cpupool::Builder;
 use tokio_core::reactor::Core;
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Interval;
+use tokio_core::reactor::Timeout;
 
 use rdkafka::Message;
+use rdkafka::consumer::BaseConsumer;
+use rdkafka::consumer::CommitMode;
 use rdkafka::consumer::Consumer;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
 use rdkafka::message::OwnedMessage;
+use rdkafka::producer::DeliveryFuture;
 use rdkafka::producer::FutureProducer;
+use rdkafka::topic_partition_list::Offset;
+use rdkafka::topic_partition_list::TopicPartitionList;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CString;
-use std::ffi::CStr;
+use std::mem;
+use std::os::raw::c_char;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 use jq::ffi::*;
-use cli::TopicMetadata;
+use jq::jq_compile_with_args;
+use jq::jq_halt_status;
+use jq::jv_invalid_message;
+use jq::uses_variable;
+use cli::ErrorAction;
+use cli::ErrorPolicy;
+use cli::OrderingMode;
+use cli::Partitioner;
+use cli::ProcessorConfig;
+use cli::Route;
 use cli::SinkMetadata;
+use cli::Sampling;
 use cli::SerializationType;
+use cli::StartPosition;
+use cli::TombstonePolicy;
+use output::OutputExpressions;
+use output::OutputRecord;
+use output::eval_key_expression;
+use output::jv_to_output_record;
+use output::resolve_topic;
+use delivery::QUEUE_FULL_BLOCK_MS;
+use delivery::Delivery;
+use delivery::deliver;
+use bounds::Bounds;
+use checkpoint::Checkpoint;
+use error::ErrorCounters;
+use error::ProcessingError;
+use inflight::InFlight;
+use murmur2::PartitionCounts;
+use murmur2::murmur2_partition;
+use offsets::OffsetTracker;
+use ordering::Sequencer;
+use properties::connection_properties;
+use ratelimit::KeyRateLimiter;
+use ratelimit::RateLimiter;
+use sampling::sample_by_key;
+use sampling::sample_randomly;
+use rebalance::Generations;
+use rebalance::RebalanceContext;
+use rebalance::Rebalances;
+use shutdown::Shutdown;
 
 use bson::bson_to_jv;
-use bson::jv_to_bson;
 use extbson::to_bson;
-use extbson::from_bson;
 
-fn str_to_jv(payload: &str, length: usize) -> jv {
-    let c_string = CString::new(payload).unwrap();
-    let c_str_ptr = c_string.as_ptr();
-    unsafe { jv_parse_sized(c_str_ptr, length as i32) }
-}
+// The consumer's default `session.timeout.ms`, which the consumer properties may override.
+const SESSION_TIMEOUT_MS: u64 = 6000;
 
-fn jv_to_string_bytes<'a>(jv_value: jv) -> Option<&'a [u8]> {
-    let json_as_string = unsafe { jv_dump_string(jv_value, 0) };
-    let c_msg = unsafe { jv_string_value(json_as_string) };
-    let result = unsafe { CStr::from_ptr(c_msg).to_str().unwrap() };
-    // cleanup
-    unsafe { jv_free(json_as_string) };
-    Some(result.as_bytes())
-}
+// How many input topics, or topic and key pairs when the route programs read `$key`, each worker
+// keeps the route programs of.
+const MAX_COMPILED_ROUTES: usize = 256;
 
-fn jv_to_bson_bytes<'a>(jv_value: jv) -> Option<&'a [u8]> {
-    jv_to_bson(jv_value).and_then(|bson| from_bson(bson).ok())
+fn str_to_jv(payload: &str) -> jv {
+    // Sized parsing needs no terminating NUL, so payloads holding one simply fail to parse
+    unsafe { jv_parse_sized(payload.as_ptr() as *const c_char, payload.len() as i32) }
 }
 
-fn jv_to_bytes<'a>(jv_value: jv, serialization: &SerializationType) -> Option<&'a [u8]> {
-    match serialization {
-        &SerializationType::JSON => jv_to_string_bytes(jv_value),
-        &SerializationType::BSON => jv_to_bson_bytes(jv_value),
-    }
+// The outputs of one message: the records of every route, in the same order as the routes, and
+// the exit code if one of the jq programs halted on it.
+struct Computation {
+    route_results: Vec<Vec<OutputRecord>>,
+    halt_code: Option<i32>,
 }
 
-fn exec_jq_expr<'a>(
+fn exec_jq_expr(
     parsed_json: jv,
     output_serialization: &SerializationType,
+    envelope: bool,
     jq_state: *mut jq_state,
-) -> Result<Vec<&'a [u8]>, Canceled> {
+    expressions: OutputExpressions,
+) -> Result<(Vec<OutputRecord>, Option<i32>), ProcessingError> {
     let mut vec = Vec::with_capacity(10);
-    if unsafe { jv_get_kind(parsed_json) == jv_kind::JV_KIND_INVALID } {
-        error!("Unable to parse json");
-        unsafe { jv_free(parsed_json) };
-        Err(Canceled)
-    } else {
-        unsafe {
-            // this consumes parsed_json
-            jq_start(jq_state, parsed_json, 0);
-        };
-        let mut result = unsafe { jq_next(jq_state) };
-        while unsafe { jv_get_kind(result) != jv_kind::JV_KIND_INVALID } {
-            // this consumes result
-            match jv_to_bytes(result, output_serialization) {
-                Some(msg) => vec.push(msg),
-                None => error!("Unable to transform JV to bytes"),
-            }
-            result = unsafe { jq_next(jq_state) };
+    let mut serialization_error = None;
+    unsafe {
+        // this consumes parsed_json
+        jq_start(jq_state, parsed_json, 0);
+    };
+    let mut result = unsafe { jq_next(jq_state) };
+    while unsafe { jv_get_kind(result) != jv_kind::JV_KIND_INVALID } {
+        // this consumes result
+        match jv_to_output_record(result, envelope, output_serialization, expressions) {
+            Ok(record) => vec.push(record),
+            Err(err) => serialization_error = serialization_error.or(Some(err)),
         }
-        Ok(vec)
+        result = unsafe { jq_next(jq_state) };
+    }
+    // An invalid value ends the output; it only carries a message when the program raised an
+    // error, in which case the results produced so far are discarded.
+    if let Some(message) = jv_invalid_message(result) {
+        return Err(ProcessingError::jq_runtime(message));
+    }
+    // A message succeeds or fails as a whole: when one of its results cannot be serialized, the
+    // valid ones are dropped too, so that a dead-lettered message is never also partly produced.
+    if let Some(err) = serialization_error {
+        return Err(err);
     }
+    // `halt` and `halt_error` end the output too, but keep what was produced before them.
+    let halt_code = jq_halt_status(jq_state).map(|(code, message)| {
+        match message {
+            Some(message) => warn!("jq program halted with exit code {}: {}", code, message),
+            None => info!("jq program halted with exit code {}", code),
+        }
+        code
+    });
+    Ok((vec, halt_code))
 }
 
-fn jq_computation<'a>(
-    msg: &'a OwnedMessage,
+fn decode_payload<M: Message>(
+    msg: &M,
     input_serialization: &SerializationType,
-    output_serialization: &SerializationType,
-    jq_state: *mut jq_state,
-) -> Result<Vec<&'a [u8]>, Canceled> {
-    match input_serialization {
+) -> Result<jv, ProcessingError> {
+    let parsed_json = match input_serialization {
         &SerializationType::BSON => match msg.payload_view::<[u8]>() {
             Some(Ok(payload)) => {
                 let try_bson = to_bson(payload);
                 match try_bson {
-                    Ok(bson) => exec_jq_expr(bson_to_jv(&bson), output_serialization, jq_state),
-                    Err(_) => {
-                        error!("could not decode bson");
-                        Err(Canceled)
+                    Ok(bson) => bson_to_jv(&bson),
+                    Err(_) => return Err(ProcessingError::decode("could not decode bson")),
+                }
+            }
+            Some(Err(_)) => return Err(ProcessingError::decode("unreadable payload")),
+            None => return Err(ProcessingError::decode("no payload")),
+        },
+        &SerializationType::JSON => match msg.payload_view::<str>() {
+            Some(Ok(payload)) => str_to_jv(payload),
+            Some(Err(_)) => return Err(ProcessingError::decode("payload is not valid UTF-8")),
+            None => return Err(ProcessingError::decode("no payload")),
+        },
+    };
+    if unsafe { jv_get_kind(parsed_json) == jv_kind::JV_KIND_INVALID } {
+        unsafe { jv_free(parsed_json) };
+        Err(ProcessingError::decode("unable to parse json"))
+    } else {
+        Ok(parsed_json)
+    }
+}
+
+// A decoded payload handed from one worker to another. jq values are reference counted without
+// atomics, so it may only move while nothing else holds a reference to it.
+struct DecodedPayload(jv);
+
+unsafe impl Send for DecodedPayload {}
+
+impl DecodedPayload {
+    fn into_inner(self) -> jv {
+        let value = self.0;
+        mem::forget(self);
+        value
+    }
+}
+
+impl Drop for DecodedPayload {
+    fn drop(&mut self) {
+        unsafe { jv_free(self.0) };
+    }
+}
+
+// What becomes of a message before its routes run.
+enum Prepared {
+    // Nothing left to run: skipped, passed-through and sampled-out messages
+    Done(Computation),
+    // The payload to run the routes on, with its rate limiting key when one was asked for
+    Decoded(DecodedPayload, Option<Vec<u8>>),
+    // Revoked while waiting for its rate limiting key, left to the next owner of its partition
+    Revoked,
+}
+
+// A message with what its rate limiting key left of it, unless it had none to wait for.
+type KeyedMessage = (OwnedMessage, Option<Result<Prepared, ProcessingError>>);
+
+// Applies the tombstone policy, decodes the message once and samples it by key. What is left to
+// run gets its rate limiting key from `rate_key_state`, if any.
+fn prepare_computation(
+    msg: &OwnedMessage,
+    input_serialization: &SerializationType,
+    config: &ProcessorConfig,
+    key_sample: Option<(f64, *mut jq_state)>,
+    rate_key_state: Option<*mut jq_state>,
+) -> Result<Prepared, ProcessingError> {
+    let routes = &config.routes[..];
+    let input_timestamp = if config.keep_input_timestamp {
+        msg.timestamp().to_millis()
+    } else {
+        None
+    };
+    let tombstone = msg.payload().is_none();
+    match (tombstone, config.tombstones) {
+        (true, TombstonePolicy::Skip) => {
+            debug!(
+                "Skipping tombstone at {}/{}/{}",
+                msg.topic(),
+                msg.partition(),
+                msg.offset()
+            );
+            return Ok(Prepared::Done(Computation {
+                route_results: routes.iter().map(|_| Vec::new()).collect(),
+                halt_code: None,
+            }));
+        }
+        (true, TombstonePolicy::PassThrough) => {
+            let mut route_results = Vec::with_capacity(routes.len());
+            for route in routes {
+                let mut records = vec![
+                    OutputRecord {
+                        topic: None,
+                        partition: None,
+                        key: msg.key().map(|key| key.to_vec()),
+                        payload: None,
+                        timestamp: input_timestamp,
+                    },
+                ];
+                route_records(&route.sink, &mut records)?;
+                route_results.push(records);
+            }
+            return Ok(Prepared::Done(Computation {
+                route_results: route_results,
+                halt_code: None,
+            }));
+        }
+        _ => (),
+    }
+    let parsed_json = if tombstone {
+        unsafe { jv_null() }
+    } else {
+        decode_payload(msg, input_serialization)?
+    };
+    // Sampled-out messages skip the route programs, tombstones left to them are sampled on `null`
+    if let Some((ratio, key_state)) = key_sample {
+        if !sample_by_key(&eval_key_expression(parsed_json, key_state), ratio) {
+            unsafe { jv_free(parsed_json) };
+            return Ok(Prepared::Done(Computation {
+                route_results: routes.iter().map(|_| Vec::new()).collect(),
+                halt_code: None,
+            }));
+        }
+    }
+    let rate_key = rate_key_state.map(|key_state| eval_key_expression(parsed_json, key_state));
+    Ok(Prepared::Decoded(DecodedPayload(parsed_json), rate_key))
+}
+
+// Runs every route's jq program against `parsed_json`, the decoded payload of `msg`, and frees it.
+fn run_routes(
+    msg: &OwnedMessage,
+    parsed_json: jv,
+    config: &ProcessorConfig,
+    jq_states: &[*mut jq_state],
+    expressions: OutputExpressions,
+) -> Result<Computation, ProcessingError> {
+    let routes = &config.routes[..];
+    let input_timestamp = if config.keep_input_timestamp {
+        msg.timestamp().to_millis()
+    } else {
+        None
+    };
+    let tombstone = msg.payload().is_none();
+    let mut route_results = Vec::with_capacity(routes.len());
+    let mut halt_code = None;
+    for (route, state) in routes.iter().zip(jq_states) {
+        let records = exec_jq_expr(
+            unsafe { jv_copy(parsed_json) },
+            route.sink.serialization(),
+            config.envelope,
+            *state,
+            expressions,
+        );
+        match records {
+            Ok((mut records, route_halt_code)) => {
+                if tombstone {
+                    for record in records.iter_mut().filter(|record| record.key.is_none()) {
+                        record.key = msg.key().map(|key| key.to_vec());
+                    }
+                }
+                if input_timestamp.is_some() {
+                    for record in records.iter_mut() {
+                        record.timestamp = input_timestamp;
                     }
                 }
+                if let Err(err) = route_records(&route.sink, &mut records) {
+                    unsafe { jv_free(parsed_json) };
+                    return Err(err);
+                }
+                route_results.push(records);
+                halt_code = halt_code.or(route_halt_code);
             }
-            Some(Err(_)) => {
-                error!("Error processing message payload {:?}", msg);
-                Err(Canceled)
+            Err(err) => {
+                unsafe { jv_free(parsed_json) };
+                return Err(err);
             }
-            None => {
-                error!("No payload");
-                Err(Canceled)
+        }
+    }
+    unsafe { jv_free(parsed_json) };
+    Ok(Computation {
+        route_results: route_results,
+        halt_code: halt_code,
+    })
+}
+
+// Waits `delay` on the event loop, in steps of at most 100ms so that `cancel` may cut the wait
+// short.
+fn throttle<F>(delay: Duration, handle: Handle, cancel: F) -> Box<Future<Item = (), Error = ()>>
+where
+    F: Fn() -> bool + 'static,
+{
+    if delay == Duration::from_secs(0) || cancel() {
+        return Box::new(future::ok(()));
+    }
+    let step = delay.min(Duration::from_millis(100));
+    let timeout = Timeout::new(step, &handle).expect("Could not create the rate limiting timeout");
+    Box::new(
+        timeout
+            .map_err(|err| error!("Rate limiting failed: {:?}", err))
+            .and_then(move |_| throttle(delay - step, handle, cancel)),
+    )
+}
+
+fn send_record(
+    producer: &FutureProducer,
+    topic: &str,
+    partition: Option<i32>,
+    record: &OutputRecord,
+) -> DeliveryFuture {
+    producer.send_copy::<[u8], [u8]>(
+        topic,
+        partition,
+        record.payload.as_ref().map(|payload| &payload[..]),
+        record.key.as_ref().map(|key| &key[..]),
+        record.timestamp,
+        QUEUE_FULL_BLOCK_MS,
+    )
+}
+
+// The record is republished untouched. The librdkafka 0.11.3 we link against has no message
+// headers, so the failure stage, error text and source coordinates only appear in the logs.
+fn send_dead_letter(
+    producer: &FutureProducer,
+    dead_letter_topic: &str,
+    msg: &OwnedMessage,
+    block_ms: i64,
+) -> DeliveryFuture {
+    info!(
+        "Dead-lettering {}/{}/{} to {}",
+        msg.topic(),
+        msg.partition(),
+        msg.offset(),
+        dead_letter_topic
+    );
+    producer.send_copy::<[u8], [u8]>(
+        dead_letter_topic,
+        None,
+        msg.payload(),
+        msg.key(),
+        msg.timestamp().to_millis(),
+        block_ms,
+    )
+}
+
+// Logs a failed message with enough context to find it again, and applies the action the error
+// policy picked for its class. Returns whether the message counts as handled, i.e. whether its
+// offset may be committed.
+fn handle_processing_error(
+    err: ProcessingError,
+    msg: &OwnedMessage,
+    producer: &FutureProducer,
+    error_policy: &ErrorPolicy,
+    error_counters: &ErrorCounters,
+    shutdown: &Shutdown,
+    future_vector: &mut Vec<DeliveryFuture>,
+) -> bool {
+    let count = error_counters.record(err.class);
+    let payload = msg.payload().unwrap_or(&[]);
+    let preview = String::from_utf8_lossy(&payload[..payload.len().min(256)]);
+    error!(
+        "{} (#{}) at {}/{}/{}: {} (payload: {})",
+        err.class,
+        count,
+        msg.topic(),
+        msg.partition(),
+        msg.offset(),
+        err.message,
+        preview
+    );
+    match (error_policy.action(err.class), error_policy.dead_letter_topic) {
+        (ErrorAction::DeadLetter, Some(dead_letter_topic)) => {
+            future_vector.push(send_dead_letter(
+                producer,
+                dead_letter_topic,
+                msg,
+                QUEUE_FULL_BLOCK_MS,
+            ));
+            true
+        }
+        (ErrorAction::Stop, _) => {
+            error!("Stopping the pipeline after a {}", err.class);
+            shutdown.request(1);
+            false
+        }
+        _ => true,
+    }
+}
+
+// Logs the delivery reports of a message, counts its results that could not be produced, and
+// applies the action the error policy picked for delivery failures, `dead_letter` producing the
+// message to the dead letter topic. Resolves to whether the message's offset may be committed.
+fn handle_delivery_reports<F>(
+    d_reports: Vec<Result<(i32, i64), KafkaError>>,
+    msg: OwnedMessage,
+    committable: bool,
+    dead_letter: F,
+    error_policy: &ErrorPolicy,
+    error_counters: &ErrorCounters,
+    shutdown: &Shutdown,
+) -> Box<Future<Item = bool, Error = Canceled>>
+where
+    F: FnOnce(&str, &OwnedMessage) -> Delivery,
+{
+    let mut failed = false;
+    for d_report in &d_reports {
+        match *d_report {
+            Ok(_) => info!("Delivery report for result: {:?}", d_report),
+            Err(ref err) => {
+                failed = true;
+                error!(
+                    "delivery failure (#{}) for {}/{}/{}: {}",
+                    error_counters.record_delivery_failure(),
+                    msg.topic(),
+                    msg.partition(),
+                    msg.offset(),
+                    err
+                );
             }
+        }
+    }
+    if !failed {
+        return Box::new(future::ok(committable));
+    }
+    match (error_policy.on_delivery_error, error_policy.dead_letter_topic) {
+        (ErrorAction::DeadLetter, Some(dead_letter_topic)) => Box::new(
+            dead_letter(dead_letter_topic, &msg).map(move |d_report| match d_report {
+                Ok(_) => committable,
+                Err(err) => {
+                    error!(
+                        "Could not dead-letter {}/{}/{}: {}",
+                        msg.topic(),
+                        msg.partition(),
+                        msg.offset(),
+                        err
+                    );
+                    false
+                }
+            }),
+        ),
+        (ErrorAction::Stop, _) => {
+            error!("Stopping the pipeline after a delivery failure");
+            shutdown.request(1);
+            Box::new(future::ok(false))
+        }
+        _ => Box::new(future::ok(committable)),
+    }
+}
+
+// The partition to produce a record to, `None` leaving the choice to librdkafka's partitioner.
+// `partition_count` is the number of partitions of the topic of the record, when known.
+fn record_partition(
+    partitioner: Partitioner,
+    record: &OutputRecord,
+    input_partition: i32,
+    partition_count: Option<i32>,
+) -> Option<i32> {
+    match partitioner {
+        Partitioner::KeepInput => Some(input_partition),
+        Partitioner::Jq => record.partition,
+        Partitioner::Murmur2 => match (record.key.as_ref(), partition_count) {
+            (Some(key), Some(count)) => Some(murmur2_partition(key, count)),
+            _ => None,
         },
-        &SerializationType::JSON => match msg.payload_view::<str>() {
-            Some(Ok(payload)) => exec_jq_expr(
-                str_to_jv(payload, payload.len()),
-                output_serialization,
-                jq_state,
-            ),
-            Some(Err(_)) => {
-                error!("Error processing message payload {:?}", msg);
-                Err(Canceled)
+        Partitioner::Default => None,
+    }
+}
+
+// Picks the topic of every record of a sink where jq picks topics, within the routing rules. A
+// record with nowhere to go fails the whole message, so that the error policy decides what becomes
+// of it instead of its offset being committed without any output.
+fn route_records(sink: &SinkMetadata, records: &mut [OutputRecord]) -> Result<(), ProcessingError> {
+    if let SinkMetadata::DynamicTopic { ref routing, .. } = *sink {
+        for record in records.iter_mut() {
+            let topic = resolve_topic(record.topic.as_ref().map(|topic| topic.as_str()), routing)
+                .map(|topic| topic.to_owned());
+            match topic {
+                Some(topic) => record.topic = Some(topic),
+                None => {
+                    return Err(ProcessingError::routing(match record.topic {
+                        Some(ref chosen) => format!("no fallback for topic {}", chosen),
+                        None => "no topic picked and no default topic".to_owned(),
+                    }))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Runs on the CPU pool: murmur2 partitioning may have to fetch the partition count of a topic.
+fn send_records(
+    producer: &FutureProducer,
+    sink: &SinkMetadata,
+    records: &[OutputRecord],
+    partitioner: Partitioner,
+    partition_counts: Option<&PartitionCounts>,
+    input_partition: i32,
+    future_vector: &mut Vec<DeliveryFuture>,
+) {
+    for record in records {
+        let topic = match sink {
+            &SinkMetadata::StdOut => {
+                info!("{:?}", record.payload);
+                continue;
             }
-            None => {
-                error!("No payload");
-                Err(Canceled)
+            &SinkMetadata::SinkTopic { ref metadata } => metadata.name,
+            // jq picked the topic, `route_records` checked it against the routing rules.
+            &SinkMetadata::DynamicTopic { .. } => match record.topic {
+                Some(ref topic) => topic.as_str(),
+                None => continue,
+            },
+        };
+        let partition_count = match partitioner {
+            Partitioner::Murmur2 if record.key.is_some() => {
+                partition_counts.and_then(|counts| counts.count(topic))
             }
-        },
+            _ => None,
+        };
+        let partition = record_partition(partitioner, record, input_partition, partition_count);
+        // Send the result of the computation to Kafka, asynchronously.
+        future_vector.push(send_record(producer, topic, partition, record))
+    }
+}
+
+// Properties given by the user override the defaults set before.
+fn set_properties(client_config: &mut ClientConfig, properties: &[(String, String)]) {
+    for &(ref key, ref value) in properties {
+        client_config.set(key, value);
+    }
+}
+
+// Compiles the program of every route, with the topic the messages come from bound to `$topic`
+// and their key to `$key`. The programs were checked at startup, so they always compile.
+fn compile_routes(routes: &[Route], topic: &str, key: Option<&[u8]>) -> Vec<*mut jq_state> {
+    // Bytes that are not UTF-8 get replaced
+    let key = key.map(|key| String::from_utf8_lossy(key));
+    let args = [("topic", Some(topic)), ("key", key.as_ref().map(|key| &key[..]))];
+    routes
+        .iter()
+        .map(|route| unsafe {
+            let state = jq_init();
+            if !jq_compile_with_args(state, route.jq_expression, &args) {
+                panic!("Could not compile {}", route.jq_expression);
+            }
+            state
+        })
+        .collect()
+}
+
+// The route programs compiled for a topic, and for a key when `key` is given, i.e. when they read
+// `$key`. Topic patterns may match any number of topics over time, and keys are many, so past
+// `MAX_COMPILED_ROUTES` every compiled program is freed and compiled again on demand.
+fn topic_routes<'a>(
+    compiled: &'a mut HashMap<(String, Option<Vec<u8>>), Vec<*mut jq_state>>,
+    routes: &[Route],
+    topic: &str,
+    key: Option<&[u8]>,
+) -> &'a [*mut jq_state] {
+    let compiled_key = (topic.to_owned(), key.map(|key| key.to_vec()));
+    if !compiled.contains_key(&compiled_key) && compiled.len() >= MAX_COMPILED_ROUTES {
+        for (_, states) in compiled.drain() {
+            for mut state in states {
+                unsafe { jq_teardown(&mut state) };
+            }
+        }
+    }
+    &compiled
+        .entry(compiled_key)
+        .or_insert_with(|| compile_routes(routes, topic, key))[..]
+}
+
+fn assigned_partitions(consumer: &StreamConsumer<RebalanceContext>) -> Vec<(String, i32)> {
+    match consumer.assignment() {
+        Ok(assignment) => assignment
+            .elements()
+            .iter()
+            .map(|elem| (elem.topic().to_owned(), elem.partition()))
+            .collect(),
+        Err(err) => {
+            error!("Could not fetch the assignment: {:?}", err);
+            Vec::new()
+        }
+    }
+}
+
+// rdkafka 0.15 only reports the partition that reached its end, not its topic: returns the
+// assigned topics that have that partition, with their position.
+fn eof_candidates(
+    consumer: &StreamConsumer<RebalanceContext>,
+    partition: i32,
+) -> Vec<(String, Option<i64>)> {
+    let topics: Vec<String> = assigned_partitions(consumer)
+        .into_iter()
+        .filter(|&(_, assigned)| assigned == partition)
+        .map(|(topic, _)| topic)
+        .collect();
+    if topics.len() <= 1 {
+        return topics.into_iter().map(|topic| (topic, None)).collect();
+    }
+    let positions = match consumer.position() {
+        Ok(positions) => positions,
+        Err(err) => {
+            error!("Could not fetch the consumer position: {:?}", err);
+            return Vec::new();
+        }
+    };
+    topics
+        .into_iter()
+        .map(|topic| {
+            let position = match positions.find_partition(&topic, partition) {
+                Some(elem) => match elem.offset() {
+                    Offset::Offset(position) => Some(position),
+                    _ => None,
+                },
+                None => None,
+            };
+            (topic, position)
+        })
+        .collect()
+}
+
+// When several topics may have reached the end of the partition, the ones whose position reached
+// the high watermark did. Asks the brokers, so it runs on the CPU pool, with a client of its own
+// since the stream consumer cannot be shared with the workers.
+fn eof_topics(
+    watermarks: &BaseConsumer,
+    candidates: Vec<(String, Option<i64>)>,
+    partition: i32,
+) -> Vec<String> {
+    candidates
+        .into_iter()
+        .filter(|&(ref topic, position)| {
+            let watermark = watermarks.fetch_watermarks(topic, partition, Duration::from_secs(1));
+            match (position, watermark) {
+                (Some(position), Ok((_, high))) => position >= high,
+                _ => false,
+            }
+        })
+        .map(|(topic, _)| topic)
+        .collect()
+}
+
+fn reached_eof(
+    bounds: &RefCell<Bounds>,
+    consumer: &StreamConsumer<RebalanceContext>,
+    shutdown: &Shutdown,
+    topics: Vec<String>,
+    partition: i32,
+) {
+    let mut bounds = bounds.borrow_mut();
+    for topic in topics {
+        bounds.reached_eof(&topic, partition);
+    }
+    if bounds.is_done(|| assigned_partitions(consumer)) {
+        info!("Every partition reached its end, stopping");
+        shutdown.request(0);
+    }
+}
+
+// Returns whether every offset that moved got committed. Offsets are only acknowledged once the
+// commit succeeded, by the commit callback for asynchronous commits, so that failed commits are
+// retried by the next one.
+fn commit_offsets(
+    consumer: &StreamConsumer<RebalanceContext>,
+    offsets: &mut OffsetTracker,
+    mode: CommitMode,
+) -> bool {
+    let committable = offsets.committable();
+    if committable.is_empty() {
+        return true;
+    }
+    let mut topic_partition_list = TopicPartitionList::new();
+    for &(ref topic, partition, offset) in &committable {
+        topic_partition_list.add_partition_offset(topic, partition, Offset::Offset(offset));
+    }
+    let sync = match mode {
+        CommitMode::Sync => true,
+        CommitMode::Async => false,
+    };
+    match consumer.commit(&topic_partition_list, mode) {
+        Ok(()) => {
+            if sync {
+                for &(ref topic, partition, offset) in &committable {
+                    offsets.committed(topic, partition, offset);
+                }
+            }
+            debug!("Committed offsets {:?}", committable);
+            true
+        }
+        Err(err) => {
+            error!("Could not commit offsets {:?}: {:?}", committable, err);
+            false
+        }
+    }
+}
+
+// Stores the offsets that moved in the checkpoint file, in place of the consumer group.
+fn checkpoint_offsets(checkpoint: &mut Checkpoint, offsets: &mut OffsetTracker) {
+    let committable = offsets.committable();
+    if committable.is_empty() {
+        return;
+    }
+    checkpoint.update(&committable);
+    match checkpoint.save() {
+        Ok(()) => {
+            for &(ref topic, partition, offset) in &committable {
+                offsets.committed(topic, partition, offset);
+            }
+            debug!("Checkpointed offsets {:?}", committable)
+        }
+        Err(err) => error!("Could not write the checkpoint {}: {}", checkpoint.path(), err),
+    }
+}
+
+// Once the work in flight is delivered, and the messages fetched before the revocation are out of
+// the stream, commits what the revoked partitions processed and forgets their state, then lets the
+// consumer give them up.
+fn handle_rebalances(
+    rebalances: &Rebalances,
+    in_flight: &InFlight,
+    consumer: &StreamConsumer<RebalanceContext>,
+    offsets: &RefCell<OffsetTracker>,
+    sequencer: &RefCell<Sequencer>,
+    bounds: &RefCell<Bounds>,
+) {
+    rebalances.poll_events();
+    for (topic, partition, offset) in rebalances.take_commits() {
+        offsets.borrow_mut().committed(&topic, partition, offset);
+    }
+    if in_flight.messages() > 0 {
+        return;
+    }
+    if let Some(revocation) = rebalances.take_revocation() {
+        if !commit_offsets(consumer, &mut offsets.borrow_mut(), CommitMode::Sync) {
+            error!("Revocation commit failed, the next owners will process the messages again");
+        }
+        offsets.borrow_mut().clear();
+        sequencer.borrow_mut().clear();
+        bounds.borrow_mut().forget_partitions();
+        let _ = revocation.send(());
+    }
+}
+
+// Whether random sampling drops a message. Tombstones that `--on-tombstone` skips or passes
+// through are left to it: dropping them would keep deletes from reaching the output topics.
+fn sampled_out_randomly(sampling: Sampling, tombstones: TombstonePolicy, tombstone: bool) -> bool {
+    match sampling {
+        Sampling::Random(_) if tombstone && tombstones != TombstonePolicy::Null => false,
+        Sampling::Random(ratio) => !sample_randomly(ratio),
+        _ => false,
     }
 }
 
+// Splits the shutdown timeout between draining the pipeline and flushing the producer, leaving the
+// rest to the final commit, so that all three fit before the hard exit on a signal.
+fn shutdown_budget(shutdown_timeout: Duration) -> (Duration, Duration) {
+    (shutdown_timeout * 3 / 4, shutdown_timeout / 8)
+}
+
+// librdkafka 0.11 joins groups with JoinGroup v0, whose rebalance timeout is the session
+// timeout: members that take longer to give their partitions up are kicked out of the group. So
+// revoked partitions are handed over within half of it, or the shutdown timeout if shorter.
+fn revoke_timeout(config: &ProcessorConfig) -> Duration {
+    let session_timeout = config
+        .consumer_properties
+        .iter()
+        .rev()
+        .find(|&&(ref key, _)| key == "session.timeout.ms")
+        .and_then(|&(_, ref value)| value.parse::<u64>().ok())
+        .unwrap_or(SESSION_TIMEOUT_MS);
+    config.shutdown_timeout.min(Duration::from_millis(session_timeout / 2))
+}
+
 // Creates all the resources and runs the event loop. The event loop will:
 //   1) receive a stream of messages from the `StreamConsumer`.
 //   2) filter out eventual Kafka errors.
 //   3) send the message to a thread pool for processing.
-//   4) produce the result to the output topic.
+//   4) produce the result of every route to its output topic.
+//   5) commit the input offset once every result has been delivered.
 // Moving each message from one stage of the pipeline to next one is handled by the event loop,
 // that runs on a single thread. The expensive CPU-bound computation is handled by the `CpuPool`,
 // without blocking the event loop.
-pub fn run_async_processor(
-    brokers: &str,
-    group_id: &str,
-    input_topic: &'static TopicMetadata,
-    output_topic: &'static SinkMetadata,
-    jq_expression: &str,
-    parallelism: usize,
-) {
+pub fn run_async_processor(config: &'static ProcessorConfig<'static>) -> i32 {
+    let input_topics = &config.input_topics;
+    let routes = &config.routes[..];
+    let error_policy = &config.error_policy;
+    let delivery_policy = &config.delivery_policy;
+
     // Create the event loop. The event loop will run on a single thread and drive the pipeline.
     let mut core = Core::new().unwrap();
 
-    // Initial jq state
-    thread_local!(static jq_state: *mut jq_state = unsafe { jq_init() };);
+    // Initial jq states, one per route, compiled for each input topic on its first message, and
+    // for each key too when the programs read `$key`
+    thread_local!(
+        static jq_states: RefCell<HashMap<(String, Option<Vec<u8>>), Vec<*mut jq_state>>> =
+            RefCell::new(HashMap::new());
+    );
+    // jq state for the optional output topic expression
+    thread_local!(static topic_jq_state: *mut jq_state = unsafe { jq_init() };);
+    // jq state for the optional output timestamp expression
+    thread_local!(static timestamp_jq_state: *mut jq_state = unsafe { jq_init() };);
+    // jq state for the optional sampling key expression
+    thread_local!(static sample_key_jq_state: *mut jq_state = unsafe { jq_init() };);
+    // jq state for the optional rate limiting key expression
+    thread_local!(static rate_limit_key_jq_state: *mut jq_state = unsafe { jq_init() };);
 
-    let arc_jq_expr = Arc::new(Mutex::new(CString::new(jq_expression).unwrap()));
+    // All routes share the same routing rules, so the first one tells us whether jq picks topics.
+    let topic_expression = routes.iter().filter_map(|r| r.sink.topic_expression()).next();
+    // `$key` is bound when the programs are compiled, which the others only need once per topic
+    let keyed_routes = routes
+        .iter()
+        .any(|route| uses_variable(route.jq_expression, "key"));
 
     // Create the CPU pool, for CPU-intensive message processing.
     let cpu_pool = Builder::new()
-        .pool_size(parallelism)
+        .pool_size(config.parallelism)
         .after_start(move || {
-            jq_state.with(|state| unsafe {
-                let cloned_jq_expr = arc_jq_expr.clone();
-                let locked_jq_expr = cloned_jq_expr.lock().unwrap();
-                let borrowed_jq_expr = locked_jq_expr.clone().into_raw();
-                jq_compile(*state, borrowed_jq_expr);
-            });
+            if let Some(topic_expression) = topic_expression {
+                topic_jq_state.with(|state| unsafe {
+                    let topic_jq_expr = CString::new(topic_expression).unwrap();
+                    jq_compile(*state, topic_jq_expr.as_ptr());
+                });
+            }
+            if let Some(timestamp_expression) = config.timestamp_expression {
+                timestamp_jq_state.with(|state| unsafe {
+                    let timestamp_jq_expr = CString::new(timestamp_expression).unwrap();
+                    jq_compile(*state, timestamp_jq_expr.as_ptr());
+                });
+            }
+            if let Sampling::ByKey(sample_key, _) = config.sampling {
+                sample_key_jq_state.with(|state| unsafe {
+                    let sample_key_jq_expr = CString::new(sample_key).unwrap();
+                    jq_compile(*state, sample_key_jq_expr.as_ptr());
+                });
+            }
+            if let Some(rate_limit_key) = config.rate_limit_key {
+                rate_limit_key_jq_state.with(|state| unsafe {
+                    let rate_limit_key_jq_expr = CString::new(rate_limit_key).unwrap();
+                    jq_compile(*state, rate_limit_key_jq_expr.as_ptr());
+                });
+            }
         })
         .create();
 
+    let bounds = Rc::new(RefCell::new(Bounds::new(
+        config.exit_on_eof,
+        config.max_messages,
+        config.until_timestamp,
+    )));
+    let partition_eof = if bounds.borrow().watches_eof() {
+        "true"
+    } else {
+        "false"
+    };
+
     // Create the `StreamConsumer`, to receive the messages from the topic in form of a `Stream`.
-    let consumer = ClientConfig::new()
-        .set("group.id", group_id)
-        .set("bootstrap.servers", brokers)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false")
-        .create::<StreamConsumer<_>>()
-        .expect("Consumer creation failed");
-
-    consumer
-        .subscribe(&[input_topic.name])
-        .expect("Can't subscribe to specified topic");
+    // It is shared with the commit and rebalance loops below.
+    let mut consumer_config = ClientConfig::new();
+    consumer_config
+        .set("group.id", config.group_id)
+        .set("bootstrap.servers", config.input_brokers)
+        .set("enable.partition.eof", partition_eof)
+        .set("session.timeout.ms", &SESSION_TIMEOUT_MS.to_string())
+        .set("enable.auto.commit", "false");
+    set_properties(&mut consumer_config, &config.consumer_properties);
+    let (context, rebalances) = RebalanceContext::new(revoke_timeout(config));
+    let rebalances = Rc::new(rebalances);
+    // Unknown or invalid properties only show up here, so they get a readable error.
+    let consumer = match consumer_config.create_with_context::<_, StreamConsumer<_>>(context) {
+        Ok(consumer) => Rc::new(consumer),
+        Err(err) => {
+            error!("Could not create the consumer: {}", err);
+            return 1;
+        }
+    };
+
+    // Runs with a checkpoint file never touch the consumer group: they assign their partitions
+    // and store their offsets locally.
+    let checkpoint = match config.checkpoint_file.map(Checkpoint::load) {
+        Some(Ok(checkpoint)) => Some(checkpoint),
+        Some(Err(err)) => {
+            error!("Could not load the checkpoint file: {}", err);
+            return 1;
+        }
+        None => None,
+    };
+    if config.start == StartPosition::Committed && config.partitions.is_none()
+        && checkpoint.is_none()
+    {
+        if let Err(err) = consumer.subscribe(&input_topics.names()) {
+            error!("Could not subscribe to the input topics: {}", err);
+            return 1;
+        }
+    } else {
+        let partitions = config.partitions.as_ref().map(|partitions| &partitions[..]);
+        let assigned = assignment::assign(
+            &consumer,
+            &input_topics.names(),
+            partitions,
+            &config.start,
+            checkpoint.as_ref(),
+        );
+        if let Err(err) = assigned {
+            error!("Could not assign the input partitions: {}", err);
+            return 1;
+        }
+    }
+    let checkpoint = checkpoint.map(|checkpoint| Rc::new(RefCell::new(checkpoint)));
+    // Only needed to tell which topic reached the end of a partition.
+    let watermark_consumer = if bounds.borrow().watches_eof() {
+        match consumer_config.create::<BaseConsumer>() {
+            Ok(watermarks) => Some(Arc::new(watermarks)),
+            Err(err) => {
+                error!("Could not create the watermark consumer: {}", err);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
 
     // Create the `FutureProducer` to produce asynchronously.
-    let producer = ClientConfig::new()
-        .set("bootstrap.servers", brokers)
-        .set("produce.offset.report", "true")
-        .create::<FutureProducer<_>>()
-        .expect("Producer creation error");
+    let mut producer_config = ClientConfig::new();
+    producer_config
+        .set("bootstrap.servers", config.output_brokers)
+        .set("produce.offset.report", "true");
+    if config.ordering != OrderingMode::Unordered {
+        // Retries would otherwise reorder the batches of a partition.
+        producer_config.set("max.in.flight.requests.per.connection", "1");
+    }
+    set_properties(&mut producer_config, &config.producer_properties);
+    let producer = match producer_config.create::<FutureProducer<_>>() {
+        Ok(producer) => producer,
+        Err(err) => {
+            error!("Could not create the producer: {}", err);
+            return 1;
+        }
+    };
+    // The linked librdkafka has no murmur2 partitioner, the partitions are computed from the
+    // partition counts of the output topics instead, as seen by the output brokers.
+    let partition_counts = if config.partitioner == Partitioner::Murmur2 {
+        let mut metadata_config = ClientConfig::new();
+        metadata_config.set("bootstrap.servers", config.output_brokers);
+        // Only the settings to reach the output brokers, the rest are for the producer
+        set_properties(
+            &mut metadata_config,
+            &connection_properties(&config.producer_properties),
+        );
+        match metadata_config.create::<BaseConsumer>() {
+            Ok(consumer) => Some(Arc::new(PartitionCounts::new(consumer))),
+            Err(err) => {
+                error!("Could not create the output metadata consumer: {}", err);
+                return 1;
+            }
+        }
+    } else {
+        None
+    };
 
     // Create a handle to the core, that will be used to provide additional asynchronous work
     // to the event loop.
     let handle = core.handle();
 
-    // Create the outer pipeline on the message stream.
-    let processed_stream = consumer
-        .start()
-        .filter_map(|result| {
+    let shutdown = Arc::new(Shutdown::new());
+    shutdown::handle_signals(shutdown.clone(), config.shutdown_timeout);
+    let error_counters = Arc::new(ErrorCounters::default());
+    // Offset of the first message a jq program halted on, by topic and partition; nothing after
+    // it gets committed, nor produced once it is recorded. Unless `--ordering partition` holds
+    // them back, later messages of the partition may have produced before that.
+    let halt_offsets: Arc<Mutex<HashMap<(String, i32), i64>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let in_flight = Rc::new(InFlight::new(
+        config.max_in_flight,
+        config.max_in_flight_bytes,
+    ));
+    let offsets = Rc::new(RefCell::new(OffsetTracker::new()));
+    let sequencer = Rc::new(RefCell::new(Sequencer::new(config.ordering)));
+    let mut rate_limiter = RateLimiter::new(config.max_rate, config.max_rate_bytes);
+    // The workers evaluate the rate limiting key, the event loop delays the busy keys.
+    let key_rate_limiter = config
+        .max_rate_per_key
+        .map(|rate| Rc::new(RefCell::new(KeyRateLimiter::new(rate))));
+
+    // Periodically commit what has been fully delivered.
+    let commit_consumer = consumer.clone();
+    let commit_offset_tracker = offsets.clone();
+    let commit_checkpoint = checkpoint.clone();
+    let commit_loop = Interval::new(config.commit_interval, &handle)
+        .expect("Could not create the commit interval")
+        .for_each(move |_| {
+            match commit_checkpoint {
+                Some(ref checkpoint) => checkpoint_offsets(
+                    &mut checkpoint.borrow_mut(),
+                    &mut commit_offset_tracker.borrow_mut(),
+                ),
+                None => {
+                    commit_offsets(
+                        &commit_consumer,
+                        &mut commit_offset_tracker.borrow_mut(),
+                        CommitMode::Async,
+                    );
+                }
+            }
+            Ok(())
+        })
+        .map_err(|err| error!("Commit loop failed: {:?}", err));
+    handle.spawn(commit_loop);
+
+    // Finish revocations even when no message comes, since the consumer waits for them.
+    let rebalance_loop = {
+        let (rebalances, in_flight) = (rebalances.clone(), in_flight.clone());
+        let (consumer, offsets) = (consumer.clone(), offsets.clone());
+        let (sequencer, bounds) = (sequencer.clone(), bounds.clone());
+        Interval::new(Duration::from_millis(100), &handle)
+            .expect("Could not create the rebalance interval")
+            .for_each(move |_| {
+                handle_rebalances(
+                    &rebalances,
+                    &in_flight,
+                    &consumer,
+                    &offsets,
+                    &sequencer,
+                    &bounds,
+                );
+                Ok(())
+            })
+            .map_err(|err| error!("Rebalance loop failed: {:?}", err))
+    };
+    handle.spawn(rebalance_loop);
+
+    // Create the outer pipeline on the message stream. The consumer wakes the stream up every
+    // 100ms even without messages, so that a shutdown request is noticed promptly.
+    let stream_shutdown = shutdown.clone();
+    let processed_stream = Generations::new(
+        consumer.start_with(Duration::from_millis(100), true),
+        rebalances.clone(),
+    ).take_while(move |_| Ok(!stream_shutdown.is_requested()))
+        .filter_map(|(result, generation)| {
             // Filter out errors
             match result {
-                Ok(msg) => Some(msg),
+                Ok(msg) => Some((msg, generation)),
+                Err(KafkaError::NoMessageReceived) => None,
+                Err(KafkaError::PartitionEOF(partition)) => {
+                    let candidates = eof_candidates(&consumer, partition);
+                    match watermark_consumer {
+                        Some(ref watermarks) if candidates.len() > 1 => {
+                            let watermarks = watermarks.clone();
+                            let (bounds, consumer) = (bounds.clone(), consumer.clone());
+                            let shutdown = shutdown.clone();
+                            let check = cpu_pool
+                                .spawn_fn(move || {
+                                    Ok::<_, ()>(eof_topics(&watermarks, candidates, partition))
+                                })
+                                .map(move |topics| {
+                                    reached_eof(&bounds, &consumer, &shutdown, topics, partition)
+                                });
+                            handle.spawn(check);
+                        }
+                        _ => {
+                            let topics = candidates.into_iter().map(|(topic, _)| topic).collect();
+                            reached_eof(&bounds, &consumer, &shutdown, topics, partition);
+                        }
+                    }
+                    None
+                }
                 Err(kafka_error) => {
                     error!("Error while receiving from Kafka: {:?}", kafka_error);
                     None
                 }
             }
         })
-        .for_each(|msg| {
+        .for_each(|(msg, generation)| {
+            handle_rebalances(
+                &rebalances,
+                &in_flight,
+                &consumer,
+                &offsets,
+                &sequencer,
+                &bounds,
+            );
+            if !rebalances.accepts(msg.topic(), msg.partition(), generation) {
+                debug!(
+                    "Dropping {}/{}/{}, it was not fetched within the current assignment",
+                    msg.topic(),
+                    msg.partition(),
+                    msg.offset()
+                );
+                return Box::new(future::ok(())) as Box<Future<Item = (), Error = ()>>;
+            }
+            {
+                let mut bounds = bounds.borrow_mut();
+                let accepted =
+                    bounds.accept(msg.topic(), msg.partition(), msg.timestamp().to_millis());
+                // Sampled first, so that `--max-messages` only counts the messages kept
+                let sampled_out = accepted && sampled_out_randomly(
+                    config.sampling,
+                    config.tombstones,
+                    msg.payload().is_none(),
+                );
+                if accepted && !sampled_out {
+                    bounds.count();
+                }
+                if bounds.is_done(|| assigned_partitions(&consumer)) {
+                    info!("Bounded run complete, stopping");
+                    shutdown.request(0);
+                }
+                if !accepted {
+                    return Box::new(future::ok(())) as Box<Future<Item = (), Error = ()>>;
+                }
+                if sampled_out {
+                    // Nothing to wait for, its offset is committable right away
+                    let mut offsets = offsets.borrow_mut();
+                    offsets.consumed(msg.topic(), msg.partition(), msg.offset());
+                    offsets.done(msg.topic(), msg.partition(), msg.offset());
+                    return Box::new(future::ok(())) as Box<Future<Item = (), Error = ()>>;
+                }
+            }
             // Process each message
             info!("Enqueuing message for computation");
             let producer = producer.clone();
+            let retry_producer = producer.clone();
+            let retry_handle = handle.clone();
+            let shutdown = shutdown.clone();
+            let retry_shutdown = shutdown.clone();
+            let throttle_shutdown = shutdown.clone();
+            let stuck_shutdown = shutdown.clone();
+            let stuck_rebalances = rebalances.clone();
+            let failed_shutdown = shutdown.clone();
+            let error_counters = error_counters.clone();
+            let retry_error_counters = error_counters.clone();
+            let halt_offsets = halt_offsets.clone();
+            let in_flight = in_flight.clone();
+            let offsets = offsets.clone();
+            let halt_tracker = offsets.clone();
             let owned_message = msg.detach();
-            // Create the inner pipeline, that represents the processing of a single event.
-            let process_message = cpu_pool
-                .spawn_fn(move || {
-                    jq_state.with(|state| {
-                        let computation_results = jq_computation(
+            let message_bytes = msg.payload().map_or(0, |payload| payload.len());
+            let send_pool = cpu_pool.clone();
+            let partition_counts = partition_counts.clone();
+            let turn = sequencer
+                .borrow_mut()
+                .next_turn(msg.topic(), msg.partition(), msg.key());
+            let (topic, partition, offset) =
+                (msg.topic().to_owned(), msg.partition(), msg.offset());
+            offsets.borrow_mut().consumed(&topic, partition, offset);
+            // The rate limiting key needs the payload decoded first, which the routes then reuse
+            let keyed_message = match key_rate_limiter {
+                Some(ref limiter) => {
+                    let (limiter, handle) = (limiter.clone(), handle.clone());
+                    let key_shutdown = shutdown.clone();
+                    let (key_rebalances, key_in_flight) = (rebalances.clone(), in_flight.clone());
+                    let key_topic = topic.clone();
+                    let key_job = cpu_pool.spawn_fn(move || {
+                        let input_serialization = input_topics
+                            .serialization(owned_message.topic())
+                            .unwrap_or(&SerializationType::JSON);
+                        let key_sample = match config.sampling {
+                            Sampling::ByKey(_, ratio) => {
+                                Some((ratio, sample_key_jq_state.with(|state| *state)))
+                            }
+                            _ => None,
+                        };
+                        let prepared = prepare_computation(
                             &owned_message,
-                            &(input_topic.serialization),
-                            &(output_topic.serialization()),
-                            *state,
+                            input_serialization,
+                            config,
+                            key_sample,
+                            Some(rate_limit_key_jq_state.with(|state| *state)),
                         );
-                        match computation_results {
-                            Err(_) => {
-                                error!("JQ Computation failed");
-                                join_all(Vec::with_capacity(0))
+                        Ok::<_, Canceled>((owned_message, prepared))
+                    });
+                    // Busy keys wait on the event loop rather than on a worker, and leave their
+                    // room in flight to the other keys meanwhile.
+                    Box::new(key_job.and_then(move |(owned_message, prepared)| {
+                        let delay = match prepared {
+                            Ok(Prepared::Decoded(_, Some(ref key))) => {
+                                limiter.borrow_mut().delay(key)
                             }
-                            Ok(ref results) => {
-                                info!("Sending result");
-                                let mut future_vector = Vec::with_capacity(results.len());
-                                for computation_result in results {
-                                    match output_topic {
-                                        &SinkMetadata::StdOut => info!("{:?}", computation_result),
-                                        // Send the result of the computation to Kafka, asynchronously.
-                                        &SinkMetadata::SinkTopic { ref metadata } => future_vector
-                                            .push(producer.send_copy::<[u8], ()>(
-                                                metadata.name,
-                                                None,
-                                                Some(&computation_result),
-                                                None,
-                                                None,
-                                                1000,
-                                            )),
+                            _ => None,
+                        };
+                        let delay = match delay {
+                            Some(delay) => delay,
+                            None => {
+                                return Box::new(future::ok((owned_message, Some(prepared))))
+                                    as Box<Future<Item = KeyedMessage, Error = Canceled>>
+                            }
+                        };
+                        key_in_flight.park(message_bytes);
+                        let revoked =
+                            move || !key_rebalances.accepts(&key_topic, partition, generation);
+                        let cancel = {
+                            let revoked = revoked.clone();
+                            move || key_shutdown.is_requested() || revoked()
+                        };
+                        Box::new(throttle(delay, handle, cancel).then(move |_| {
+                            key_in_flight.unpark(message_bytes);
+                            let prepared = if revoked() {
+                                Ok(Prepared::Revoked)
+                            } else {
+                                prepared
+                            };
+                            Ok::<_, Canceled>((owned_message, Some(prepared)))
+                        }))
+                    })) as Box<Future<Item = KeyedMessage, Error = Canceled>>
+                }
+                None => Box::new(future::ok((owned_message, None))),
+            };
+            let compute_pool = cpu_pool.clone();
+            // Create the inner pipeline, that represents the processing of a single event.
+            let process_message = keyed_message
+                .and_then(move |(owned_message, prepared)| {
+                    compute_pool.spawn_fn(move || {
+                        jq_states.with(|states| {
+                            let computation_results = {
+                                let mut states = states.borrow_mut();
+                                let topic = owned_message.topic();
+                                let key = if keyed_routes {
+                                    owned_message.key()
+                                } else {
+                                    None
+                                };
+                                let route_states =
+                                    topic_routes(&mut states, routes, topic, key);
+                                let expressions = OutputExpressions {
+                                    topic: topic_expression
+                                        .map(|_| topic_jq_state.with(|state| *state)),
+                                    timestamp: config
+                                        .timestamp_expression
+                                        .map(|_| timestamp_jq_state.with(|state| *state)),
+                                };
+                                let input_serialization = input_topics
+                                    .serialization(topic)
+                                    .unwrap_or(&SerializationType::JSON);
+                                let key_sample = match config.sampling {
+                                    Sampling::ByKey(_, ratio) => {
+                                        Some((ratio, sample_key_jq_state.with(|state| *state)))
                                     }
+                                    _ => None,
+                                };
+                                let prepared = prepared.unwrap_or_else(|| {
+                                    prepare_computation(
+                                        &owned_message,
+                                        input_serialization,
+                                        config,
+                                        key_sample,
+                                        None,
+                                    )
+                                });
+                                match prepared {
+                                    Ok(Prepared::Done(computation)) => Some(Ok(computation)),
+                                    Ok(Prepared::Decoded(parsed_json, _)) => Some(run_routes(
+                                        &owned_message,
+                                        parsed_json.into_inner(),
+                                        config,
+                                        route_states,
+                                        expressions,
+                                    )),
+                                    Ok(Prepared::Revoked) => None,
+                                    Err(err) => Some(Err(err)),
+                                }
+                            };
+                            Ok::<_, Canceled>((owned_message, computation_results))
+                        })
+                    })
+                })
+                .and_then(move |(owned_message, computation_results)| {
+                    // Computing happened concurrently, producing waits for earlier messages
+                    turn.ready().map(move |turn| (owned_message, computation_results, turn))
+                })
+                .and_then(move |(owned_message, computation_results, turn)| {
+                    send_pool.spawn_fn(move || {
+                        let mut future_vector = Vec::new();
+                        let partition_key =
+                            (owned_message.topic().to_owned(), owned_message.partition());
+                        let past_halt = halt_offsets
+                            .lock()
+                            .unwrap()
+                            .get(&partition_key)
+                            .map_or(false, |&halt_offset| owned_message.offset() > halt_offset);
+                        let mut halted = false;
+                        // Whether the input offset may be committed once the results are delivered
+                        let committable = match computation_results {
+                            // The partition ended at an earlier message, nothing after it counts
+                            // from now on
+                            _ if past_halt => false,
+                            // Revoked while waiting for its key, the next owner processes it
+                            None => false,
+                            Some(Err(err)) => handle_processing_error(
+                                err,
+                                &owned_message,
+                                &producer,
+                                error_policy,
+                                &error_counters,
+                                &shutdown,
+                                &mut future_vector,
+                            ),
+                            Some(Ok(ref computation)) => {
+                                info!("Sending result");
+                                let route_results = &computation.route_results;
+                                for (route, records) in routes.iter().zip(route_results) {
+                                    send_records(
+                                        &producer,
+                                        &route.sink,
+                                        records,
+                                        config.partitioner,
+                                        partition_counts.as_ref().map(|counts| &**counts),
+                                        owned_message.partition(),
+                                        &mut future_vector,
+                                    );
+                                }
+                                if let Some(exit_code) = computation.halt_code {
+                                    let mut halt_offsets = halt_offsets.lock().unwrap();
+                                    let halt_offset = halt_offsets
+                                        .entry(partition_key)
+                                        .or_insert(owned_message.offset());
+                                    *halt_offset = (*halt_offset).min(owned_message.offset());
+                                    halted = true;
+                                    shutdown.request(exit_code);
                                 }
-                                join_all(future_vector)
+                                true
                             }
-                        }
+                        };
+                        Ok::<_, Canceled>(
+                            (owned_message, future_vector, committable, halted, turn),
+                        )
                     })
                 })
-                .and_then(|d_reports| {
-                    // Once the message has been produced, print the delivery report and terminate
-                    // the pipeline.
-                    for d_report in &d_reports {
-                        info!("Delivery report for result: {:?}", d_report);
+                .and_then(move |(owned_message, future_vector, committable, halted, turn)| {
+                    if halted {
+                        // Stops committing right away, before later messages get done
+                        info!(
+                            "Committing {}/{} up to halting offset {}",
+                            owned_message.topic(),
+                            owned_message.partition(),
+                            owned_message.offset()
+                        );
+                        halt_tracker.borrow_mut().stop_at(
+                            owned_message.topic(),
+                            owned_message.partition(),
+                            owned_message.offset() + 1,
+                        );
+                    }
+                    // Retries wait on the event loop, so the delivery reports are awaited here
+                    // rather than on the CPU pool.
+                    let (producer, handle) = (retry_producer.clone(), retry_handle.clone());
+                    let deliveries = future_vector.into_iter().map(move |delivery| {
+                        deliver(producer.clone(), delivery, delivery_policy, handle.clone(), 1)
+                    });
+                    let dead_letter = move |dead_letter_topic: &str, msg: &OwnedMessage| {
+                        // Dead letters sent from the event loop must not block it
+                        let delivery =
+                            send_dead_letter(&retry_producer, dead_letter_topic, msg, 0);
+                        deliver(retry_producer, delivery, delivery_policy, retry_handle, 1)
+                    };
+                    join_all(deliveries)
+                        .and_then(move |d_reports| {
+                            handle_delivery_reports(
+                                d_reports,
+                                owned_message,
+                                committable,
+                                dead_letter,
+                                error_policy,
+                                &retry_error_counters,
+                                &retry_shutdown,
+                            )
+                        })
+                        .map(move |committable| {
+                            // The next message of the group may only produce once every result
+                            // of this one, retries and dead letter included, is delivered.
+                            drop(turn);
+                            committable
+                        })
+                })
+                .map(move |committable| {
+                    if committable {
+                        offsets.borrow_mut().done(&topic, partition, offset);
+                    } else if !stuck_shutdown.is_requested()
+                        && stuck_rebalances.accepts(&topic, partition, generation)
+                    {
+                        // Nothing after an offset that is never done gets committed, so going on
+                        // would only pile up messages to process again on restart. Revoked
+                        // partitions are processed again by their next owner anyway.
+                        error!(
+                            "{}/{} is stuck at offset {}, stopping the pipeline",
+                            topic,
+                            partition,
+                            offset
+                        );
+                        stuck_shutdown.request(1);
                     }
-                    Ok(())
                 })
-                .or_else(|err| {
+                .or_else(move |err| {
                     // In case of error, this closure will be executed instead.
-                    error!("Error while processing message: {:?}", err);
+                    error!("Error while processing message: {:?}, stopping the pipeline", err);
+                    failed_shutdown.request(1);
                     Ok(())
                 });
             // Spawns the inner pipeline in the same event pool.
-            handle.spawn(process_message);
-            Ok(())
+            in_flight.acquire(message_bytes);
+            let room = in_flight.wait_for_room();
+            let key_pause = key_rate_limiter
+                .as_ref()
+                .and_then(|limiter| limiter.borrow_mut().pause());
+            let room = match rate_limiter.delay(message_bytes).into_iter().chain(key_pause).max() {
+                Some(delay) => {
+                    let throttle_rebalances = rebalances.clone();
+                    // A revocation needs the stream to run dry, which it cannot while paused
+                    let cancel = move || {
+                        throttle_shutdown.is_requested() || throttle_rebalances.is_revoking()
+                    };
+                    let pause = throttle(delay, handle.clone(), cancel);
+                    Box::new(room.join(pause).map(|_| ())) as Box<Future<Item = (), Error = ()>>
+                }
+                None => room,
+            };
+            handle.spawn(process_message.then(move |result| {
+                in_flight.release(message_bytes);
+                result
+            }));
+            // Not polling the stream is enough to pause consumption: rdkafka 0.15 cannot pause
+            // partitions, but its poll thread blocks on the channel that feeds the stream, and
+            // librdkafka stops fetching once `queued.max.messages.kbytes` is buffered.
+            room
         });
 
     info!("Starting event loop");
     // Runs the event pool until the consumer terminates.
     core.run(processed_stream).unwrap();
-    info!("Stream processing terminated");
+
+    // Let the messages already handed to the CPU pool finish, so that their results are
+    // delivered before anything is committed. The unfinished ones are not committed, and are
+    // processed again on restart.
+    let (drain_timeout, flush_timeout) = shutdown_budget(config.shutdown_timeout);
+    let drain_deadline = Instant::now() + drain_timeout;
+    while in_flight.messages() > 0 {
+        if Instant::now() >= drain_deadline {
+            warn!(
+                "Giving up on {} messages still in flight after {:?}",
+                in_flight.messages(),
+                drain_timeout
+            );
+            break;
+        }
+        core.turn(Some(Duration::from_millis(100)));
+    }
+    producer.flush(flush_timeout);
+
+    match checkpoint {
+        Some(ref checkpoint) => {
+            checkpoint_offsets(&mut checkpoint.borrow_mut(), &mut offsets.borrow_mut())
+        }
+        None => {
+            commit_offsets(&consumer, &mut offsets.borrow_mut(), CommitMode::Sync);
+        }
+    }
+    rebalances.close();
+
+    // Dropping the consumer on return closes it, which leaves the consumer group right away
+    // instead of waiting for the session to time out.
+    info!("Stream processing terminated ({})", error_counters);
+    shutdown.exit_code()
 }
 
 #[cfg(test)]
 #[macro_use]
 extern crate proptest;
-mod tests {}
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use rdkafka::message::OwnedMessage;
+    use rdkafka::message::Timestamp;
+
+    use cli::Partitioner;
+    use cli::Sampling;
+    use cli::SerializationType;
+    use cli::SinkMetadata;
+    use cli::TombstonePolicy;
+    use cli::TopicRouting;
+    use decode_payload;
+    use error::ErrorClass;
+    use error::ProcessingError;
+    use exec_jq_expr;
+    use murmur2::murmur2_partition;
+    use jq::testing::compile;
+    use jq::testing::parse;
+    use output::OutputExpressions;
+    use output::OutputRecord;
+    use record_partition;
+    use route_records;
+    use sampled_out_randomly;
+    use shutdown_budget;
+
+    fn run(
+        program: &str,
+        input: &str,
+    ) -> Result<(Vec<OutputRecord>, Option<i32>), ProcessingError> {
+        exec_jq_expr(
+            parse(input),
+            &SerializationType::JSON,
+            false,
+            compile(program),
+            OutputExpressions::default(),
+        )
+    }
+
+    fn record(partition: Option<i32>) -> OutputRecord {
+        OutputRecord {
+            topic: None,
+            partition: partition,
+            key: None,
+            payload: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn it_picks_the_output_partition() {
+        assert_eq!(record_partition(Partitioner::KeepInput, &record(Some(3)), 5, None), Some(5));
+        assert_eq!(record_partition(Partitioner::Jq, &record(Some(3)), 5, None), Some(3));
+        assert_eq!(record_partition(Partitioner::Jq, &record(None), 5, None), None);
+        assert_eq!(record_partition(Partitioner::Default, &record(Some(3)), 5, Some(8)), None);
+
+        let mut keyed = record(None);
+        keyed.key = Some(b"21".to_vec());
+        let murmur2 = Some(murmur2_partition(b"21", 10));
+        assert_eq!(record_partition(Partitioner::Murmur2, &keyed, 5, Some(10)), murmur2);
+        assert_eq!(record_partition(Partitioner::Murmur2, &keyed, 5, None), None);
+        assert_eq!(record_partition(Partitioner::Murmur2, &record(Some(3)), 5, Some(10)), None);
+    }
+
+    #[test]
+    fn it_fails_results_with_nowhere_to_go() {
+        let sink = SinkMetadata::DynamicTopic {
+            serialization: SerializationType::JSON,
+            routing: TopicRouting {
+                default_topic: None,
+                topic_expression: None,
+                allowed_topics: Some(vec!["orders"]),
+                fallback_topic: None,
+            },
+        };
+        let routed = |topic: Option<&str>| {
+            let mut records = vec![record(None)];
+            records[0].topic = topic.map(|topic| topic.to_owned());
+            route_records(&sink, &mut records).map(|_| records[0].topic.clone())
+        };
+        assert_eq!(routed(Some("orders")).unwrap(), Some("orders".to_owned()));
+        assert_eq!(routed(Some("refunds")).unwrap_err().class, ErrorClass::Routing);
+        assert_eq!(routed(None).unwrap_err().class, ErrorClass::Routing);
+    }
+
+    #[test]
+    fn it_leaves_skipped_and_passed_through_tombstones_to_their_policy() {
+        let nothing = Sampling::Random(0.0);
+        assert!(!sampled_out_randomly(nothing, TombstonePolicy::PassThrough, true));
+        assert!(!sampled_out_randomly(nothing, TombstonePolicy::Skip, true));
+        assert!(sampled_out_randomly(nothing, TombstonePolicy::Null, true));
+        assert!(sampled_out_randomly(nothing, TombstonePolicy::PassThrough, false));
+        assert!(!sampled_out_randomly(Sampling::All, TombstonePolicy::Null, true));
+    }
+
+    #[test]
+    fn it_leaves_time_to_commit_before_the_hard_exit() {
+        let timeout = Duration::from_secs(30);
+        let (drain_timeout, flush_timeout) = shutdown_budget(timeout);
+        assert!(drain_timeout + flush_timeout < timeout);
+    }
+
+    #[test]
+    fn it_rejects_a_payload_holding_a_nul_byte() {
+        let payload = b"{\"a\": 1}\0".to_vec();
+        let msg = OwnedMessage::new(
+            Some(payload),
+            None,
+            "input".to_owned(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+        );
+        let err = decode_payload(&msg, &SerializationType::JSON).unwrap_err();
+        assert_eq!(err.class, ErrorClass::Decode);
+    }
+
+    #[test]
+    fn it_reports_jq_runtime_errors() {
+        let err = run(r#"error("bad")"#, "{}").unwrap_err();
+        assert_eq!(err.class, ErrorClass::JqRuntime);
+        assert_eq!(err.message, "bad");
+
+        let err = run("error({a: 1})", "{}").unwrap_err();
+        assert_eq!(err.class, ErrorClass::JqRuntime);
+        assert_eq!(err.message, r#"{"a":1}"#);
+    }
+
+    #[test]
+    fn it_tells_an_empty_output_from_an_error() {
+        let (records, halt_code) = run("empty", "{}").unwrap();
+        assert!(records.is_empty());
+        assert_eq!(halt_code, None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use rdkafka::consumer::BaseConsumer;
+use rdkafka::consumer::Consumer;
+
+const METADATA_TIMEOUT_SECS: u64 = 10;
+// How long a partition count is trusted, so that partitions added to a topic get used.
+const PARTITION_COUNT_TTL_SECS: u64 = 300;
+
+/// The murmur2 hash of the Java clients' `Utils.murmur2`, which their default partitioner applies
+/// to record keys.
+pub fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+    let mut h = SEED ^ data.len() as u32;
+    let mut chunks = data.chunks(4);
+    let tail = if data.len() % 4 == 0 {
+        &[][..]
+    } else {
+        chunks.next_back().unwrap()
+    };
+    for chunk in chunks {
+        let mut k = u32::from(chunk[0]) | u32::from(chunk[1]) << 8 | u32::from(chunk[2]) << 16
+            | u32::from(chunk[3]) << 24;
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= u32::from(byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+/// The partition the Java clients' default partitioner picks for `key`.
+pub fn murmur2_partition(key: &[u8], partition_count: i32) -> i32 {
+    (murmur2(key) & 0x7fff_ffff) % partition_count
+}
+
+/// The partition counts of the output topics, fetched from the output brokers and cached for a
+/// few minutes. The linked librdkafka has no murmur2 partitioner, so murmur2 partitions are
+/// computed here and given to the producer explicitly.
+pub struct PartitionCounts {
+    consumer: BaseConsumer,
+    counts: Mutex<HashMap<String, (i32, Instant)>>,
+}
+
+impl PartitionCounts {
+    /// `consumer` only fetches metadata, and must talk to the output brokers.
+    pub fn new(consumer: BaseConsumer) -> PartitionCounts {
+        PartitionCounts {
+            consumer: consumer,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The number of partitions of `topic`, or `None` while the brokers know no such topic. Blocks
+    /// while the metadata is fetched; when that fails, a stale count is better than none.
+    pub fn count(&self, topic: &str) -> Option<i32> {
+        let cached = self.counts.lock().unwrap().get(topic).cloned();
+        let ttl = Duration::from_secs(PARTITION_COUNT_TTL_SECS);
+        match cached {
+            Some((count, fetched)) if fetched.elapsed() < ttl => return Some(count),
+            _ => (),
+        }
+        // Fetched without the lock, so that the other topics do not wait for this one
+        let metadata = self.consumer
+            .fetch_metadata(Some(topic), Duration::from_secs(METADATA_TIMEOUT_SECS));
+        let count = match metadata {
+            Ok(metadata) => metadata
+                .topics()
+                .iter()
+                .find(|metadata_topic| metadata_topic.name() == topic)
+                .map_or(0, |metadata_topic| metadata_topic.partitions().len() as i32),
+            Err(err) => {
+                warn!("Could not fetch the partition count of {}: {}", topic, err);
+                return cached.map(|(count, _)| count);
+            }
+        };
+        if count == 0 {
+            warn!("{} has no partitions yet, letting librdkafka pick them", topic);
+            return None;
+        }
+        self.counts
+            .lock()
+            .unwrap()
+            .insert(topic.to_owned(), (count, Instant::now()));
+        Some(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use murmur2::murmur2;
+    use murmur2::murmur2_partition;
+
+    #[test]
+    fn it_hashes_like_the_java_clients() {
+        // The test vectors of Kafka's `UtilsTest`
+        assert_eq!(murmur2(b"21"), -973932308);
+        assert_eq!(murmur2(b"foobar"), -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string"), -1486304829);
+        assert_eq!(
+            murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"),
+            -58897971
+        );
+        assert_eq!(murmur2(b"abc"), 479470107);
+    }
+
+    #[test]
+    fn it_picks_partitions_like_the_java_clients() {
+        assert_eq!(murmur2_partition(b"21", 10), (-973932308i32 & 0x7fff_ffff) % 10);
+        assert!((0..1000).all(|key: u32| {
+            let partition = murmur2_partition(key.to_string().as_bytes(), 7);
+            partition >= 0 && partition < 7
+        }));
+    }
+}
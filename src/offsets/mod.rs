@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+/// Tracks, per partition, which consumed offsets are fully processed, i.e. every output they
+/// produced has a successful delivery report. Offsets complete in any order, but a partition is
+/// only committable up to its oldest offset that is still in flight.
+#[derive(Default)]
+pub struct OffsetTracker {
+    partitions: HashMap<(String, i32), PartitionOffsets>,
+}
+
+#[derive(Default)]
+struct PartitionOffsets {
+    // consumed offsets that cannot be committed yet, and whether each of them is done
+    pending: BTreeMap<i64, bool>,
+    // the offset to commit next: one past the last done offset with nothing pending before it
+    committable: Option<i64>,
+    // the last offset the brokers, or the checkpoint file, acknowledged
+    committed: Option<i64>,
+    // offsets from here on are never committed
+    limit: Option<i64>,
+}
+
+impl PartitionOffsets {
+    fn advance(&mut self) {
+        loop {
+            let first = match self.pending.iter().next() {
+                Some((&offset, &true)) if self.limit.map_or(true, |limit| offset < limit) => {
+                    offset
+                }
+                _ => break,
+            };
+            self.pending.remove(&first);
+            self.committable = Some(first + 1);
+        }
+    }
+}
+
+impl OffsetTracker {
+    pub fn new() -> OffsetTracker {
+        OffsetTracker::default()
+    }
+
+    /// Registers an offset handed to the pipeline. Must be called in consumption order.
+    pub fn consumed(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partitions
+            .entry((topic.to_owned(), partition))
+            .or_insert_with(PartitionOffsets::default)
+            .pending
+            .insert(offset, false);
+    }
+
+    /// Marks an offset as fully processed.
+    pub fn done(&mut self, topic: &str, partition: i32, offset: i64) {
+        if let Some(partition_offsets) = self.partitions.get_mut(&(topic.to_owned(), partition)) {
+            if let Some(done) = partition_offsets.pending.get_mut(&offset) {
+                *done = true;
+            }
+            partition_offsets.advance();
+        }
+    }
+
+    /// Never commits `topic`/`partition` past `offset`, whatever completes later.
+    pub fn stop_at(&mut self, topic: &str, partition: i32, offset: i64) {
+        if let Some(partition_offsets) = self.partitions.get_mut(&(topic.to_owned(), partition)) {
+            let limit = partition_offsets.limit.map_or(offset, |limit| limit.min(offset));
+            partition_offsets.limit = Some(limit);
+            partition_offsets.committable = partition_offsets
+                .committable
+                .map(|committable| committable.min(offset));
+        }
+    }
+
+    /// Forgets every partition, once the consumer gave them up.
+    pub fn clear(&mut self) {
+        self.partitions.clear();
+    }
+
+    /// Returns the offsets that moved past the last acknowledged commit, as
+    /// `(topic, partition, offset)`. They are returned again until `committed` acknowledges them,
+    /// so that a failed commit is retried by the next one.
+    pub fn committable(&self) -> Vec<(String, i32, i64)> {
+        let mut committable = Vec::new();
+        for (&(ref topic, partition), partition_offsets) in self.partitions.iter() {
+            if let Some(offset) = partition_offsets.committable {
+                if partition_offsets.committed.map_or(true, |committed| offset > committed) {
+                    committable.push((topic.clone(), partition, offset));
+                }
+            }
+        }
+        committable
+    }
+
+    /// Acknowledges a successful commit of `offset`.
+    pub fn committed(&mut self, topic: &str, partition: i32, offset: i64) {
+        if let Some(partition_offsets) = self.partitions.get_mut(&(topic.to_owned(), partition)) {
+            let committed = partition_offsets.committed.map_or(offset, |old| old.max(offset));
+            partition_offsets.committed = Some(committed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use offsets::OffsetTracker;
+
+    #[test]
+    fn it_commits_past_done_offsets() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.consumed("input", 0, 11);
+        tracker.done("input", 0, 10);
+        tracker.done("input", 0, 11);
+
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 12)]);
+        tracker.committed("input", 0, 12);
+        assert!(tracker.committable().is_empty());
+    }
+
+    #[test]
+    fn it_commits_again_until_acknowledged() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.done("input", 0, 10);
+
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 11)]);
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 11)]);
+    }
+
+    #[test]
+    fn it_waits_for_earlier_offsets() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.consumed("input", 0, 11);
+        tracker.consumed("input", 0, 12);
+        tracker.done("input", 0, 11);
+        tracker.done("input", 0, 12);
+
+        assert!(tracker.committable().is_empty());
+
+        tracker.done("input", 0, 10);
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 13)]);
+    }
+
+    #[test]
+    fn it_tracks_partitions_independently() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.consumed("input", 1, 20);
+        tracker.done("input", 1, 20);
+
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 1, 21)]);
+    }
+
+    #[test]
+    fn it_does_not_commit_past_a_stop() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.consumed("input", 0, 11);
+        tracker.consumed("input", 0, 12);
+        tracker.done("input", 0, 10);
+        tracker.stop_at("input", 0, 11);
+        tracker.stop_at("input", 0, 13);
+        tracker.done("input", 0, 11);
+        tracker.done("input", 0, 12);
+
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 11)]);
+    }
+
+    #[test]
+    fn it_forgets_revoked_partitions() {
+        let mut tracker = OffsetTracker::new();
+        tracker.consumed("input", 0, 10);
+        tracker.clear();
+        tracker.consumed("input", 0, 20);
+        tracker.done("input", 0, 20);
+
+        assert_eq!(tracker.committable(), vec![("input".to_owned(), 0, 21)]);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use futures::Future;
+use futures::future;
+use futures::sync::oneshot;
+use futures::sync::oneshot::Canceled;
+
+use cli::OrderingMode;
+use prune::MIN_PRUNE_AT;
+use prune::prune_if_grown;
+
+/// Hands out turns to produce, so that messages of the same ordering group produce their results
+/// in consumption order even though the CPU pool processes them concurrently. The event loop
+/// takes the turns in consumption order and waits for them before producing.
+pub struct Sequencer {
+    mode: OrderingMode,
+    // the turn of the last message consumed in each group, by topic, partition and key
+    last_turns: HashMap<(String, i32, Option<Vec<u8>>), oneshot::Receiver<()>>,
+    prune_at: usize,
+}
+
+/// A message's turn to produce. `ready` resolves once every earlier message of the same group has
+/// its results delivered; dropping the turn lets the next one go.
+pub struct Turn {
+    previous: Option<oneshot::Receiver<()>>,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl Sequencer {
+    pub fn new(mode: OrderingMode) -> Sequencer {
+        Sequencer {
+            mode: mode,
+            last_turns: HashMap::new(),
+            prune_at: MIN_PRUNE_AT,
+        }
+    }
+
+    /// Must be called in consumption order. Messages without a key are ordered by partition in
+    /// `Key` mode.
+    pub fn next_turn(&mut self, topic: &str, partition: i32, key: Option<&[u8]>) -> Turn {
+        let group = match self.mode {
+            OrderingMode::Unordered => {
+                return Turn {
+                    previous: None,
+                    done: None,
+                }
+            }
+            OrderingMode::Partition => (topic.to_owned(), partition, None),
+            OrderingMode::Key => (topic.to_owned(), partition, key.map(|key| key.to_vec())),
+        };
+        let (done, turn) = oneshot::channel();
+        let previous = self.last_turns.insert(group, turn);
+        // Groups whose last message already produced are done with.
+        prune_if_grown(&mut self.last_turns, &mut self.prune_at, |_, turn| {
+            match turn.try_recv() {
+                Ok(None) => true,
+                _ => false,
+            }
+        });
+        Turn {
+            previous: previous,
+            done: Some(done),
+        }
+    }
+
+    /// Forgets every group, once the consumer gave their partitions up.
+    pub fn clear(&mut self) {
+        self.last_turns.clear();
+        self.prune_at = MIN_PRUNE_AT;
+    }
+}
+
+impl Turn {
+    pub fn ready(mut self) -> Box<Future<Item = Turn, Error = Canceled>> {
+        match self.previous.take() {
+            // a cancelled turn means the previous message gave up; ours comes anyway
+            Some(previous) => Box::new(previous.then(move |_| Ok::<_, Canceled>(self))),
+            None => Box::new(future::ok(self)),
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use futures::Future;
+
+    use cli::OrderingMode;
+    use ordering::Sequencer;
+    use prune::MIN_PRUNE_AT;
+
+    #[test]
+    fn it_orders_turns_within_a_partition() {
+        let mut sequencer = Sequencer::new(OrderingMode::Partition);
+        let first = sequencer.next_turn("input", 0, Some(b"a"));
+        let second = sequencer.next_turn("input", 0, Some(b"b"));
+        let other = sequencer.next_turn("input", 1, Some(b"a"));
+
+        assert!(second.previous.is_some());
+        assert!(other.previous.is_none());
+        other.ready().wait().unwrap();
+
+        drop(first);
+        second.ready().wait().unwrap();
+    }
+
+    #[test]
+    fn it_orders_turns_by_key() {
+        let mut sequencer = Sequencer::new(OrderingMode::Key);
+        let _first = sequencer.next_turn("input", 0, Some(b"a"));
+
+        assert!(sequencer.next_turn("input", 0, Some(b"b")).previous.is_none());
+        assert!(sequencer.next_turn("input", 0, Some(b"a")).previous.is_some());
+    }
+
+    #[test]
+    fn it_forgets_finished_groups() {
+        let mut sequencer = Sequencer::new(OrderingMode::Key);
+        for key in 0..2000u32 {
+            let key = key.to_string();
+            sequencer.next_turn("input", 0, Some(key.as_bytes()));
+        }
+
+        assert!(sequencer.last_turns.len() <= MIN_PRUNE_AT);
+    }
+}
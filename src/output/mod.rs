@@ -0,0 +1,345 @@
+extern crate bson;
+
+use self::bson::Bson;
+use self::bson::encode_document;
+
+use jq::ffi::*;
+use jq::jv_get_kind;
+use jq::jv_is_integer;
+use jq::jv_number_value;
+use jq::jv_object_get;
+use jq::jv_string;
+use jq::jv_string_value;
+use bson::jv_to_bson;
+use cli::SerializationType;
+use cli::TopicRouting;
+use cli::parse_timestamp;
+use error::ProcessingError;
+
+const ENVELOPE_TOPIC_KEY: &'static str = "topic";
+const ENVELOPE_KEY_KEY: &'static str = "key";
+const ENVELOPE_VALUE_KEY: &'static str = "value";
+const ENVELOPE_PARTITION_KEY: &'static str = "partition";
+
+/// A single record produced by a jq program, ready to be written to a sink.
+///
+/// `topic` is `None` when the jq program did not pick a destination, in which case the
+/// sink's fixed topic (or the fallback topic) is used. `partition` is only set by an envelope,
+/// and `timestamp` is `None` when the record gets the time it is produced at.
+#[derive(Debug)]
+pub struct OutputRecord {
+    pub topic: Option<String>,
+    pub partition: Option<i32>,
+    pub key: Option<Vec<u8>>,
+    pub payload: Option<Vec<u8>>,
+    pub timestamp: Option<i64>,
+}
+
+/// The compiled jq expressions evaluated against every result: the one picking its topic, and
+/// the one extracting its timestamp.
+#[derive(Clone, Copy, Default)]
+pub struct OutputExpressions {
+    pub topic: Option<*mut jq_state>,
+    pub timestamp: Option<*mut jq_state>,
+}
+
+pub fn jv_to_string_bytes(jv_value: jv) -> Option<Vec<u8>> {
+    let json_as_string = unsafe { jv_dump_string(jv_value, 0) };
+    let result = jv_string_value(&json_as_string).as_bytes().to_vec();
+    // cleanup
+    unsafe { jv_free(json_as_string) };
+    Some(result)
+}
+
+pub fn jv_to_bson_bytes(jv_value: jv) -> Option<Vec<u8>> {
+    match jv_to_bson(jv_value) {
+        Some(Bson::Document(doc)) => {
+            let mut bytes = Vec::new();
+            encode_document(&mut bytes, &doc).ok().map(|_| bytes)
+        }
+        _ => None,
+    }
+}
+
+pub fn jv_to_bytes(jv_value: jv, serialization: &SerializationType) -> Option<Vec<u8>> {
+    match serialization {
+        &SerializationType::JSON => jv_to_string_bytes(jv_value),
+        &SerializationType::BSON => jv_to_bson_bytes(jv_value),
+    }
+}
+
+// Keys are written as raw bytes when they are strings, so that jq can produce the same keys a
+// Java client would. Any other value is dumped as JSON.
+fn jv_to_key_bytes(jv_value: jv) -> Option<Vec<u8>> {
+    if jv_get_kind(jv_value) == jv_kind::JV_KIND_STRING {
+        let key = jv_string_value(&jv_value).as_bytes().to_vec();
+        unsafe { jv_free(jv_value) };
+        Some(key)
+    } else {
+        jv_to_string_bytes(jv_value)
+    }
+}
+
+fn envelope_field(envelope: jv, field: &str) -> Option<jv> {
+    let field_key = jv_string(field.to_owned());
+    let value = jv_object_get(envelope, field_key);
+    unsafe { jv_free(field_key) };
+    value
+}
+
+fn envelope_partition(envelope: jv) -> Option<i32> {
+    envelope_field(envelope, ENVELOPE_PARTITION_KEY).and_then(|partition| {
+        let valid = jv_get_kind(partition) == jv_kind::JV_KIND_NUMBER
+            && jv_is_integer(partition) && jv_number_value(partition) >= 0.0;
+        let result = if valid {
+            Some(jv_number_value(partition) as i32)
+        } else {
+            warn!("Ignoring invalid partition in envelope");
+            None
+        };
+        unsafe { jv_free(partition) };
+        result
+    })
+}
+
+fn envelope_topic(envelope: jv) -> Option<String> {
+    envelope_field(envelope, ENVELOPE_TOPIC_KEY).and_then(|topic| {
+        let result = if jv_get_kind(topic) == jv_kind::JV_KIND_STRING {
+            Some(jv_string_value(&topic).to_owned())
+        } else {
+            warn!("Ignoring non-string topic in envelope");
+            None
+        };
+        unsafe { jv_free(topic) };
+        result
+    })
+}
+
+// Runs the expression compiled in `state` against `value` and returns its first output, if any.
+// The outputs after it are never computed.
+fn first_output(value: jv, state: *mut jq_state) -> Option<jv> {
+    unsafe { jq_start(state, jv_copy(value), 0) };
+    let output = unsafe { jq_next(state) };
+    if jv_get_kind(output) == jv_kind::JV_KIND_INVALID {
+        unsafe { jv_free(output) };
+        None
+    } else {
+        Some(output)
+    }
+}
+
+/// Runs a key expression, such as the rate limiting or sampling key, against `value` and returns
+/// its first output as JSON, or an empty key when it has none. The state lets go of `value`
+/// afterwards, so that `value` may be handed to another thread.
+pub fn eval_key_expression(value: jv, key_state: *mut jq_state) -> Vec<u8> {
+    let key = first_output(value, key_state).and_then(jv_to_string_bytes).unwrap_or_default();
+    unsafe { jq_start(key_state, jv_null(), 0) };
+    key
+}
+
+// Takes the first output of the timestamp expression, either milliseconds since the epoch or an
+// ISO-8601 date.
+fn eval_timestamp_expression(result: jv, timestamp_state: *mut jq_state) -> Option<i64> {
+    let output = match first_output(result, timestamp_state) {
+        Some(output) => output,
+        None => {
+            warn!("Timestamp expression has no output for result");
+            return None;
+        }
+    };
+    let timestamp = match jv_get_kind(output) {
+        jv_kind::JV_KIND_NUMBER => Some(jv_number_value(output) as i64),
+        jv_kind::JV_KIND_STRING => parse_timestamp(jv_string_value(&output)),
+        _ => None,
+    };
+    if timestamp.is_none() {
+        warn!("Ignoring invalid timestamp in result");
+    }
+    unsafe { jv_free(output) };
+    timestamp
+}
+
+// Runs the topic expression against `result` and returns its first output, which must be a
+// string.
+fn eval_topic_expression(result: jv, topic_state: *mut jq_state) -> Option<String> {
+    first_output(result, topic_state).and_then(|topic| {
+        let result = if jv_get_kind(topic) == jv_kind::JV_KIND_STRING {
+            Some(jv_string_value(&topic).to_owned())
+        } else {
+            warn!("Ignoring non-string topic from the topic expression");
+            None
+        };
+        unsafe { jv_free(topic) };
+        result
+    })
+}
+
+/// Turns one jq result into an `OutputRecord`, or a serialization error. This consumes `result`.
+///
+/// When `envelope` is set the result must be an object whose `value` field holds the payload,
+/// and whose optional `key` and `topic` fields pick the record key and destination topic.
+/// Otherwise the whole result is the payload. A topic picked by the envelope wins over the
+/// output topic expression, which is only evaluated when `expressions` holds one. The timestamp
+/// expression, when given, is evaluated on the whole result.
+pub fn jv_to_output_record(
+    result: jv,
+    envelope: bool,
+    serialization: &SerializationType,
+    expressions: OutputExpressions,
+) -> Result<OutputRecord, ProcessingError> {
+    let topic_state = expressions.topic;
+    let timestamp = expressions
+        .timestamp
+        .and_then(|state| eval_timestamp_expression(result, state));
+    let (topic, partition, key, value) = if envelope {
+        if jv_get_kind(result) != jv_kind::JV_KIND_OBJECT {
+            unsafe { jv_free(result) };
+            return Err(ProcessingError::serialization("envelope output must be an object"));
+        }
+        let topic = envelope_topic(result)
+            .or_else(|| topic_state.and_then(|state| eval_topic_expression(result, state)));
+        let partition = envelope_partition(result);
+        let key = envelope_field(result, ENVELOPE_KEY_KEY).and_then(jv_to_key_bytes);
+        let value = envelope_field(result, ENVELOPE_VALUE_KEY).unwrap_or(unsafe { jv_null() });
+        unsafe { jv_free(result) };
+        (topic, partition, key, value)
+    } else {
+        let topic = topic_state.and_then(|state| eval_topic_expression(result, state));
+        (topic, None, None, result)
+    };
+    // A null value, or an envelope without one, is a tombstone
+    if jv_get_kind(value) == jv_kind::JV_KIND_NULL {
+        unsafe { jv_free(value) };
+        return Ok(OutputRecord {
+            topic: topic,
+            partition: partition,
+            key: key,
+            payload: None,
+            timestamp: timestamp,
+        });
+    }
+    match jv_to_bytes(value, serialization) {
+        Some(payload) => Ok(OutputRecord {
+            topic: topic,
+            partition: partition,
+            key: key,
+            payload: Some(payload),
+            timestamp: timestamp,
+        }),
+        None => Err(ProcessingError::serialization("unable to transform JV to bytes")),
+    }
+}
+
+/// Decides which topic a record goes to. A topic chosen by jq must be in the allowed topics, if
+/// any are given, or the record goes to the fallback topic. When jq chose nothing the default
+/// topic is used, then the fallback topic. Returns `None` when the record has nowhere to go.
+pub fn resolve_topic<'a>(chosen: Option<&'a str>, routing: &'a TopicRouting<'a>) -> Option<&'a str> {
+    match chosen {
+        Some(topic) => match routing.allowed_topics {
+            Some(ref allowed_topics) if !allowed_topics.contains(&topic) => {
+                warn!("Topic {} is not in the allowed topics", topic);
+                routing.fallback_topic
+            }
+            _ => Some(topic),
+        },
+        None => routing.default_topic.or(routing.fallback_topic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cli::SerializationType;
+    use cli::TopicRouting;
+    use jq::testing::compile;
+    use jq::testing::parse;
+    use output::OutputExpressions;
+    use output::eval_key_expression;
+    use output::jv_to_output_record;
+    use output::resolve_topic;
+
+    fn routing<'a>(allowed: Option<Vec<&'a str>>, fallback: Option<&'a str>) -> TopicRouting<'a> {
+        TopicRouting {
+            default_topic: Some("default"),
+            topic_expression: None,
+            allowed_topics: allowed,
+            fallback_topic: fallback,
+        }
+    }
+
+    #[test]
+    fn it_uses_the_chosen_topic() {
+        let routing = routing(None, Some("fallback"));
+        assert_eq!(resolve_topic(Some("orders"), &routing), Some("orders"));
+    }
+
+    #[test]
+    fn it_uses_the_default_topic_when_nothing_is_chosen() {
+        let routing = routing(None, Some("fallback"));
+        assert_eq!(resolve_topic(None, &routing), Some("default"));
+    }
+
+    #[test]
+    fn it_falls_back_when_the_topic_is_not_allowed() {
+        let routing = routing(Some(vec!["orders"]), Some("fallback"));
+        assert_eq!(resolve_topic(Some("orders"), &routing), Some("orders"));
+        assert_eq!(resolve_topic(Some("refunds"), &routing), Some("fallback"));
+    }
+
+    #[test]
+    fn it_drops_topics_that_are_not_allowed_without_fallback() {
+        let routing = routing(Some(vec!["orders"]), None);
+        assert_eq!(resolve_topic(Some("refunds"), &routing), None);
+    }
+
+    #[test]
+    fn it_turns_null_into_a_tombstone() {
+        let record = jv_to_output_record(
+            parse("null"),
+            false,
+            &SerializationType::JSON,
+            OutputExpressions::default(),
+        )
+            .unwrap();
+        assert_eq!(record.payload, None);
+    }
+
+    #[test]
+    fn it_keeps_the_key_of_an_enveloped_tombstone() {
+        let result = parse(r#"{"key": "user-1", "value": null, "partition": 3}"#);
+        let expressions = OutputExpressions::default();
+        let record =
+            jv_to_output_record(result, true, &SerializationType::JSON, expressions).unwrap();
+        assert_eq!(record.key, Some(b"user-1".to_vec()));
+        assert_eq!(record.partition, Some(3));
+        assert_eq!(record.payload, None);
+    }
+
+    #[test]
+    fn it_extracts_the_timestamp_of_a_result() {
+        let expressions = OutputExpressions {
+            topic: None,
+            timestamp: Some(compile(".at")),
+        };
+        let timestamp = |json| {
+            jv_to_output_record(parse(json), false, &SerializationType::JSON, expressions)
+                .unwrap()
+                .timestamp
+        };
+        assert_eq!(timestamp(r#"{"at": 1500000000000}"#), Some(1500000000000));
+        assert_eq!(
+            timestamp(r#"{"at": "2017-07-14T02:40:00Z"}"#),
+            Some(1500000000000)
+        );
+        assert_eq!(timestamp(r#"{"at": "yesterday"}"#), None);
+        assert_eq!(timestamp(r#"{"at": [1, 2]}"#), None);
+    }
+
+    #[test]
+    fn it_keys_on_the_first_output() {
+        let key = |program, json| eval_key_expression(parse(json), compile(program));
+        assert_eq!(key(".user", r#"{"user": "alice"}"#), b"\"alice\"".to_vec());
+        assert_eq!(key(".users[]", r#"{"users": [1, 2]}"#), b"1".to_vec());
+        assert_eq!(key("empty", "{}"), Vec::<u8>::new());
+    }
+}
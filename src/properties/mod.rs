@@ -0,0 +1,256 @@
+/// Parses a `key=value` librdkafka property, as given on the command line.
+pub fn parse_property(property: &str) -> Option<(String, String)> {
+    let mut split = property.splitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(key), Some(value)) if !key.trim().is_empty() => {
+            Some((key.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => None,
+    }
+}
+
+/// Parses a properties file in the format of the Java clients: one property per line, its key
+/// separated from its value by `=`, `:` or whitespace, `#` and `!` comments, and lines continued
+/// with a trailing backslash. Escape sequences are kept as they are.
+pub fn parse_properties(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut properties = Vec::new();
+    let mut logical_line = String::new();
+    for line in contents.lines() {
+        let line = line.trim_left();
+        if logical_line.is_empty() && (line.is_empty() || line.starts_with('#')
+            || line.starts_with('!'))
+        {
+            continue;
+        }
+        // An even number of trailing backslashes are escaped ones
+        let trailing_backslashes = line.chars().rev().take_while(|&c| c == '\\').count();
+        if trailing_backslashes % 2 == 1 {
+            logical_line.push_str(&line[..line.len() - 1]);
+            continue;
+        }
+        logical_line.push_str(line);
+        properties.push(parse_logical_line(&logical_line)?);
+        logical_line.clear();
+    }
+    // The last line may still be continued at the end of the file
+    if !logical_line.trim().is_empty() {
+        properties.push(parse_logical_line(&logical_line)?);
+    }
+    Ok(properties)
+}
+
+// Splits a property of a properties file, whose key ends at the first `=`, `:` or whitespace.
+// Whitespace may surround the separator, or be the separator itself.
+fn parse_logical_line(line: &str) -> Result<(String, String), String> {
+    let line = line.trim();
+    let key_end = line.find(|c: char| c == '=' || c == ':' || c.is_whitespace())
+        .ok_or(format!("Invalid property, expected a key and a value: {}", line))?;
+    let key = &line[..key_end];
+    if key.is_empty() {
+        return Err(format!("Invalid property, expected a key: {}", line));
+    }
+    let rest = line[key_end..].trim_left();
+    let value = if rest.starts_with('=') || rest.starts_with(':') {
+        rest[1..].trim_left()
+    } else {
+        rest
+    };
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+// Settings of the Java clients that librdkafka has no equivalent for, and would refuse.
+const JAVA_ONLY_KEYS: &[&str] = &[
+    "key.serializer",
+    "value.serializer",
+    "key.deserializer",
+    "value.deserializer",
+    "interceptor.classes",
+    "ssl.truststore.location",
+    "ssl.truststore.password",
+    "ssl.truststore.type",
+    "ssl.keystore.type",
+    "sasl.login.callback.handler.class",
+    "sasl.client.callback.handler.class",
+];
+
+// The value of `name="value"` in a JAAS configuration.
+fn jaas_option(jaas_config: &str, name: &str) -> Option<String> {
+    let start = jaas_config.find(&format!("{}=\"", name))? + name.len() + 2;
+    let length = jaas_config[start..].find('"')?;
+    Some(jaas_config[start..start + length].to_owned())
+}
+
+/// Turns the properties of a Java client into librdkafka ones: the credentials of
+/// `sasl.jaas.config` become `sasl.username` and `sasl.password`, renamed settings get their
+/// librdkafka name, and the settings that only make sense in Java are dropped with a warning.
+pub fn from_java_properties(properties: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut translated = Vec::with_capacity(properties.len());
+    for (key, value) in properties {
+        match key.as_str() {
+            "sasl.jaas.config" => {
+                match (jaas_option(&value, "username"), jaas_option(&value, "password")) {
+                    (Some(username), Some(password)) => {
+                        translated.push(("sasl.username".to_owned(), username));
+                        translated.push(("sasl.password".to_owned(), password));
+                    }
+                    _ => warn!("Ignoring sasl.jaas.config, it has no username and password"),
+                }
+            }
+            "sasl.mechanism" => translated.push(("sasl.mechanisms".to_owned(), value)),
+            key if JAVA_ONLY_KEYS.contains(&key) => {
+                warn!("Ignoring {}, which only the Java clients understand", key)
+            }
+            _ => translated.push((key.clone(), value)),
+        }
+    }
+    translated
+}
+
+// Prefixes of the settings that tell a client how to reach and authenticate with the brokers,
+// which consumers and producers share.
+const CONNECTION_PREFIXES: &[&str] = &[
+    "security.protocol",
+    "ssl.",
+    "sasl.",
+    "client.id",
+    "api.version.",
+    "broker.version.fallback",
+    "socket.",
+    "metadata.",
+    "reconnect.backoff",
+];
+
+/// Keeps the settings of `properties` that any client of the same brokers needs, e.g. to build a
+/// consumer from the settings of a producer.
+pub fn connection_properties(properties: &[(String, String)]) -> Vec<(String, String)> {
+    properties
+        .iter()
+        .filter(|&&(ref key, _)| {
+            CONNECTION_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use properties::{connection_properties, from_java_properties, parse_properties,
+                     parse_property};
+
+    #[test]
+    fn it_parses_a_property() {
+        assert_eq!(
+            parse_property("sasl.mechanisms=PLAIN"),
+            Some(("sasl.mechanisms".to_owned(), "PLAIN".to_owned()))
+        );
+        assert_eq!(
+            parse_property("ssl.key.password=a=b"),
+            Some(("ssl.key.password".to_owned(), "a=b".to_owned()))
+        );
+        assert_eq!(parse_property("acks"), None);
+    }
+
+    #[test]
+    fn it_parses_a_properties_file() {
+        let contents = "# security\n\
+                        security.protocol=SASL_SSL\n\
+                        ! compression\n\
+                        compression.codec: lz4\n\
+                        \n\
+                        bootstrap.servers=broker-1:9092,\\\n    broker-2:9092\n";
+
+        assert_eq!(
+            parse_properties(contents),
+            Ok(vec![
+                ("security.protocol".to_owned(), "SASL_SSL".to_owned()),
+                ("compression.codec".to_owned(), "lz4".to_owned()),
+                (
+                    "bootstrap.servers".to_owned(),
+                    "broker-1:9092,broker-2:9092".to_owned()
+                ),
+            ])
+        );
+        assert!(parse_properties("no-value").is_err());
+        assert!(parse_properties("=value").is_err());
+    }
+
+    #[test]
+    fn it_parses_whitespace_separated_properties() {
+        assert_eq!(
+            parse_properties("acks all\nlinger.ms  =  5\n"),
+            Ok(vec![
+                ("acks".to_owned(), "all".to_owned()),
+                ("linger.ms".to_owned(), "5".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_joins_continued_lines() {
+        let contents = concat!(
+            "sasl.jaas.config=PlainLoginModule required \\\n",
+            "    username=\"alice\" \\\n",
+            "    password=\"s3cret\";\n",
+            "ssl.ca.location=C:\\\\certs\\\\\n",
+            "client.id=last \\",
+        );
+
+        assert_eq!(
+            parse_properties(contents),
+            Ok(vec![
+                (
+                    "sasl.jaas.config".to_owned(),
+                    "PlainLoginModule required username=\"alice\" password=\"s3cret\";".to_owned(),
+                ),
+                ("ssl.ca.location".to_owned(), "C:\\\\certs\\\\".to_owned()),
+                ("client.id".to_owned(), "last".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_translates_java_properties() {
+        let jaas_config = "org.apache.kafka.common.security.plain.PlainLoginModule required \
+                           username=\"alice\" password=\"s3cr=t\";";
+        let properties = vec![
+            ("sasl.jaas.config".to_owned(), jaas_config.to_owned()),
+            ("sasl.mechanism".to_owned(), "PLAIN".to_owned()),
+            (
+                "key.deserializer".to_owned(),
+                "org.apache.kafka.common.serialization.StringDeserializer".to_owned(),
+            ),
+            ("acks".to_owned(), "all".to_owned()),
+        ];
+
+        assert_eq!(
+            from_java_properties(properties),
+            vec![
+                ("sasl.username".to_owned(), "alice".to_owned()),
+                ("sasl.password".to_owned(), "s3cr=t".to_owned()),
+                ("sasl.mechanisms".to_owned(), "PLAIN".to_owned()),
+                ("acks".to_owned(), "all".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_connection_properties() {
+        let properties = vec![
+            ("security.protocol".to_owned(), "SASL_SSL".to_owned()),
+            ("sasl.username".to_owned(), "alice".to_owned()),
+            ("compression.codec".to_owned(), "lz4".to_owned()),
+            ("acks".to_owned(), "all".to_owned()),
+        ];
+
+        assert_eq!(
+            connection_properties(&properties),
+            vec![
+                ("security.protocol".to_owned(), "SASL_SSL".to_owned()),
+                ("sasl.username".to_owned(), "alice".to_owned()),
+            ]
+        );
+    }
+}
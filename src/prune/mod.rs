@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Below this many entries, maps are not worth pruning.
+pub const MIN_PRUNE_AT: usize = 1024;
+
+/// Keeps the entries of `map` passing `keep` once it grew past `prune_at`, so that keys seen once
+/// do not accumulate forever. `prune_at` then doubles what is left, so that pruning stays rare when
+/// most entries are kept.
+pub fn prune_if_grown<K, V, F>(map: &mut HashMap<K, V>, prune_at: &mut usize, keep: F)
+where
+    K: Eq + Hash,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    if map.len() > *prune_at {
+        map.retain(keep);
+        *prune_at = MIN_PRUNE_AT.max(2 * map.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use prune::MIN_PRUNE_AT;
+    use prune::prune_if_grown;
+
+    #[test]
+    fn it_prunes_once_grown() {
+        let mut map = HashMap::new();
+        let mut prune_at = MIN_PRUNE_AT;
+        for key in 0..MIN_PRUNE_AT {
+            map.insert(key, key % 2 == 0);
+        }
+        prune_if_grown(&mut map, &mut prune_at, |_, keep| *keep);
+        assert_eq!(map.len(), MIN_PRUNE_AT);
+
+        map.insert(MIN_PRUNE_AT, false);
+        prune_if_grown(&mut map, &mut prune_at, |_, keep| *keep);
+        assert_eq!(map.len(), MIN_PRUNE_AT / 2);
+        assert_eq!(prune_at, MIN_PRUNE_AT);
+    }
+}
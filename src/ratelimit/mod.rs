@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use prune::MIN_PRUNE_AT;
+use prune::prune_if_grown;
+
+// How long the messages of a key may wait for it, beyond which consumption pauses instead of
+// piling up more of them.
+const MAX_KEY_DELAY_MS: u64 = 1000;
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most one second worth of
+/// tokens. Taking more tokens than there are runs into debt, which later takes have to wait for.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let refilled = self.tokens + as_secs(now - self.updated) * self.rate;
+            self.tokens = refilled.min(self.rate);
+            self.updated = now;
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate
+    }
+
+    /// Takes `amount` tokens, and returns how long to wait until the bucket is out of debt.
+    pub fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_millis((-self.tokens / self.rate * 1000.0).ceil() as u64)
+        }
+    }
+}
+
+/// Caps the rate at which messages are consumed, in messages and in payload bytes per second.
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(max_messages: Option<f64>, max_bytes: Option<f64>) -> RateLimiter {
+        let now = Instant::now();
+        RateLimiter {
+            messages: max_messages.map(|rate| TokenBucket::new(rate, now)),
+            bytes: max_bytes.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// Counts a consumed message, and returns how long to wait before consuming the next one.
+    pub fn delay(&mut self, bytes: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let messages_delay = self.messages.as_mut().map(|bucket| bucket.take(1.0, now));
+        let bytes_delay = self.bytes.as_mut().map(|bucket| bucket.take(bytes as f64, now));
+        messages_delay
+            .into_iter()
+            .chain(bytes_delay)
+            .max()
+            .and_then(|delay| if delay > Duration::from_secs(0) { Some(delay) } else { None })
+    }
+}
+
+/// Caps the rate of messages sharing the same rate limiting key, with one bucket per key, so that
+/// the busy keys get delayed while the others go on. A key running more than a second into debt
+/// pauses consumption until it is back under that second.
+pub struct KeyRateLimiter {
+    rate: f64,
+    buckets: HashMap<Vec<u8>, TokenBucket>,
+    prune_at: usize,
+    paused_until: Option<Instant>,
+}
+
+impl KeyRateLimiter {
+    pub fn new(rate: f64) -> KeyRateLimiter {
+        KeyRateLimiter {
+            rate: rate,
+            buckets: HashMap::new(),
+            prune_at: MIN_PRUNE_AT,
+            paused_until: None,
+        }
+    }
+
+    fn delay_at(&mut self, key: &[u8], now: Instant) -> Duration {
+        let rate = self.rate;
+        let delay = self.buckets
+            .entry(key.to_vec())
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(1.0, now);
+        // Full buckets behave like new ones.
+        prune_if_grown(&mut self.buckets, &mut self.prune_at, |_, bucket| {
+            !bucket.is_full(now)
+        });
+        let max_delay = Duration::from_millis(MAX_KEY_DELAY_MS);
+        if delay > max_delay {
+            let until = now + (delay - max_delay);
+            self.paused_until = Some(self.paused_until.map_or(until, |paused| paused.max(until)));
+        }
+        delay
+    }
+
+    fn pause_at(&mut self, now: Instant) -> Duration {
+        match self.paused_until {
+            Some(until) if until > now => until - now,
+            _ => {
+                self.paused_until = None;
+                Duration::from_secs(0)
+            }
+        }
+    }
+
+    /// Counts a message with this key, and returns how long to wait before processing it.
+    pub fn delay(&mut self, key: &[u8]) -> Option<Duration> {
+        let delay = self.delay_at(key, Instant::now());
+        if delay > Duration::from_secs(0) {
+            Some(delay)
+        } else {
+            None
+        }
+    }
+
+    /// How long to pause consumption, so that no key runs deeper into debt.
+    pub fn pause(&mut self) -> Option<Duration> {
+        let pause = self.pause_at(Instant::now());
+        if pause > Duration::from_secs(0) {
+            Some(pause)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use ratelimit::KeyRateLimiter;
+    use ratelimit::TokenBucket;
+
+    #[test]
+    fn it_allows_a_burst_of_one_second_then_waits() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(1.0, start), Duration::from_secs(0));
+        }
+        assert_eq!(bucket.take(1.0, start), Duration::from_millis(100));
+        assert_eq!(
+            bucket.take(1.0, start + Duration::from_millis(100)),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            bucket.take(1.0, start + Duration::from_secs(10)),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn it_limits_keys_independently() {
+        let mut limiter = KeyRateLimiter::new(1.0);
+        let now = Instant::now();
+        assert_eq!(limiter.delay_at(b"a", now), Duration::from_secs(0));
+        assert_eq!(limiter.delay_at(b"b", now), Duration::from_secs(0));
+        assert_eq!(limiter.delay_at(b"a", now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn it_pauses_consumption_while_a_key_is_too_deep_in_debt() {
+        let mut limiter = KeyRateLimiter::new(1.0);
+        let now = Instant::now();
+        for _ in 0..2 {
+            limiter.delay_at(b"a", now);
+        }
+        assert_eq!(limiter.pause_at(now), Duration::from_secs(0));
+        assert_eq!(limiter.delay_at(b"a", now), Duration::from_secs(2));
+        assert_eq!(limiter.pause_at(now), Duration::from_secs(1));
+        assert_eq!(
+            limiter.pause_at(now + Duration::from_secs(1)),
+            Duration::from_secs(0)
+        );
+        assert!(limiter.paused_until.is_none());
+    }
+}
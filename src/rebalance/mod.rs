@@ -0,0 +1,370 @@
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use futures::Async;
+use futures::Poll;
+use futures::stream::Stream;
+use futures::task;
+use futures::task::Task;
+use rdkafka::client::ClientContext;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::consumer::Rebalance;
+use rdkafka::error::KafkaResult;
+use rdkafka::topic_partition_list::Offset;
+use rdkafka::topic_partition_list::TopicPartitionList;
+use rdkafka::types::RDKafkaTopicPartitionList;
+
+/// What the event loop hears about a rebalance, or an asynchronous commit. A revocation carries
+/// the sender that lets the consumer give its partitions up, once their work in flight is done.
+pub enum RebalanceEvent {
+    Assigned(Vec<(String, i32)>),
+    Revoked(mpsc::Sender<()>),
+    /// The consumer gave the revoked partitions up without waiting any longer
+    TimedOut,
+    /// Offsets the brokers acknowledged, as `(topic, partition, offset)`
+    Committed(Vec<(String, i32, i64)>),
+}
+
+/// Consumer context reporting rebalances and the outcome of asynchronous commits to the event
+/// loop. Its callbacks run on the polling thread of the consumer, which a revocation blocks until
+/// the event loop committed what the revoked partitions processed, or `revoke_timeout` elapsed.
+pub struct RebalanceContext {
+    events: Mutex<mpsc::Sender<RebalanceEvent>>,
+    revoke_timeout: Duration,
+}
+
+impl RebalanceContext {
+    pub fn new(revoke_timeout: Duration) -> (RebalanceContext, Rebalances) {
+        let (events, receiver) = mpsc::channel();
+        let context = RebalanceContext {
+            events: Mutex::new(events),
+            revoke_timeout: revoke_timeout,
+        };
+        (context, Rebalances::new(receiver))
+    }
+
+    fn send(&self, event: RebalanceEvent) -> bool {
+        self.events
+            .lock()
+            .expect("Rebalance events poisoned")
+            .send(event)
+            .is_ok()
+    }
+}
+
+impl ClientContext for RebalanceContext {}
+
+impl ConsumerContext for RebalanceContext {
+    fn pre_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        if let Rebalance::Revoke = *rebalance {
+            info!("Partitions revoked, finishing their work in flight");
+            let (done, handed_over) = mpsc::channel();
+            if !self.send(RebalanceEvent::Revoked(done)) {
+                return;
+            }
+            if handed_over.recv_timeout(self.revoke_timeout).is_err() {
+                warn!(
+                    "Giving the partitions up after {:?} with work still in flight",
+                    self.revoke_timeout
+                );
+                self.send(RebalanceEvent::TimedOut);
+            }
+        }
+    }
+
+    fn post_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        match *rebalance {
+            Rebalance::Assign(assignment) => {
+                let partitions: Vec<(String, i32)> = assignment
+                    .elements()
+                    .iter()
+                    .map(|elem| (elem.topic().to_owned(), elem.partition()))
+                    .collect();
+                info!("Partitions assigned: {:?}", partitions);
+                self.send(RebalanceEvent::Assigned(partitions));
+            }
+            Rebalance::Revoke => info!("Partitions handed over"),
+            Rebalance::Error(ref err) => error!("Rebalance failed: {}", err),
+        }
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: *mut RDKafkaTopicPartitionList) {
+        if let Err(err) = result {
+            error!("Could not commit offsets, retrying with the next commit: {}", err);
+            return;
+        }
+        if offsets.is_null() {
+            return;
+        }
+        // The list belongs to librdkafka, which frees it after the callback
+        let offsets = unsafe { TopicPartitionList::from_ptr(offsets) };
+        let committed = offsets
+            .elements()
+            .iter()
+            .filter(|elem| elem.error().is_ok())
+            .filter_map(|elem| match elem.offset() {
+                Offset::Offset(offset) => Some((elem.topic().to_owned(), elem.partition(), offset)),
+                _ => None,
+            })
+            .collect();
+        unsafe { offsets.leak() };
+        self.send(RebalanceEvent::Committed(committed));
+    }
+}
+
+/// The event loop side of the rebalances: whether the consumer owns the partitions its messages
+/// come from, the revocation waiting for the work in flight, and the offsets acknowledged since
+/// the last look.
+///
+/// Every revocation starts a new generation. Messages are tagged by `Generations` with the
+/// generation they were fetched in, so that the messages the consumer still buffered from before
+/// a revocation are told apart from the ones fetched again once their partition is reassigned.
+pub struct Rebalances {
+    events: RefCell<Option<mpsc::Receiver<RebalanceEvent>>>,
+    owns_partitions: Cell<bool>,
+    // the partitions of the latest assignment with the generation they were assigned in, by
+    // topic, unless partitions were never assigned
+    assignment: RefCell<Option<HashMap<String, HashMap<i32, u64>>>>,
+    revocation: RefCell<Option<mpsc::Sender<()>>>,
+    commits: RefCell<Vec<(String, i32, i64)>>,
+    generation: Cell<u64>,
+    // the generation the messages coming out of the stream were fetched in
+    fetched_generation: Cell<u64>,
+    // the stream waiting for messages, to wake up once a revocation needs it to run dry
+    stream_task: RefCell<Option<Task>>,
+}
+
+impl Rebalances {
+    fn new(events: mpsc::Receiver<RebalanceEvent>) -> Rebalances {
+        Rebalances {
+            events: RefCell::new(Some(events)),
+            owns_partitions: Cell::new(true),
+            assignment: RefCell::new(None),
+            revocation: RefCell::new(None),
+            commits: RefCell::new(Vec::new()),
+            generation: Cell::new(0),
+            fetched_generation: Cell::new(0),
+            stream_task: RefCell::new(None),
+        }
+    }
+
+    /// Whether a message consumed from `topic`/`partition`, fetched in `generation`, may be
+    /// processed. None may from a revocation until the next assignment, since the messages still
+    /// buffered by the consumer belong to partitions that may now be processed by another member
+    /// of the group. Afterwards only the messages of the assigned partitions may, provided they
+    /// were fetched since their partition was assigned: older ones are fetched again. Partitions
+    /// assigned without a group are always accepted.
+    pub fn accepts(&self, topic: &str, partition: i32, generation: u64) -> bool {
+        if !self.owns_partitions.get() {
+            return false;
+        }
+        match *self.assignment.borrow() {
+            Some(ref assignment) => assignment
+                .get(topic)
+                .and_then(|partitions| partitions.get(&partition))
+                .map_or(false, |&assigned| generation >= assigned),
+            None => true,
+        }
+    }
+
+    // The stream ran dry after `generation` started: whatever comes out of it next was fetched
+    // since.
+    fn drained(&self, generation: u64) {
+        self.fetched_generation.set(generation);
+    }
+
+    /// Applies the events received since the last call.
+    pub fn poll_events(&self) {
+        let events = self.events.borrow();
+        let events = match *events {
+            Some(ref events) => events,
+            None => return,
+        };
+        while let Ok(event) = events.try_recv() {
+            match event {
+                RebalanceEvent::Assigned(partitions) => {
+                    let generation = self.generation.get();
+                    let mut assignment = HashMap::new();
+                    for (topic, partition) in partitions {
+                        assignment
+                            .entry(topic)
+                            .or_insert_with(HashMap::new)
+                            .insert(partition, generation);
+                    }
+                    *self.assignment.borrow_mut() = Some(assignment);
+                    self.owns_partitions.set(true);
+                }
+                RebalanceEvent::Revoked(done) => {
+                    self.owns_partitions.set(false);
+                    self.generation.set(self.generation.get() + 1);
+                    *self.revocation.borrow_mut() = Some(done);
+                    // The poll thread waits for the revocation, so the stream may never wake up
+                    // by itself to run dry.
+                    if let Some(task) = self.stream_task.borrow_mut().take() {
+                        task.notify();
+                    }
+                }
+                // Nothing tells the buffered messages apart anymore, taking them for new ones
+                // risks duplicates rather than dropping messages that are not fetched again.
+                RebalanceEvent::TimedOut => self.fetched_generation.set(self.generation.get()),
+                RebalanceEvent::Committed(offsets) => self.commits.borrow_mut().extend(offsets),
+            }
+        }
+    }
+
+    /// Whether a revocation is waiting to be finished, which nothing should hold up.
+    pub fn is_revoking(&self) -> bool {
+        self.revocation.borrow().is_some()
+    }
+
+    /// Takes the revocation waiting to be finished, if any, once every message fetched before it
+    /// came out of the stream. Handing the partitions over earlier would let the consumer fetch
+    /// new messages that the stream could not tell apart from the old ones.
+    pub fn take_revocation(&self) -> Option<mpsc::Sender<()>> {
+        if self.fetched_generation.get() < self.generation.get() {
+            return None;
+        }
+        self.revocation.borrow_mut().take()
+    }
+
+    /// Takes the offsets acknowledged by the asynchronous commits since the last call.
+    pub fn take_commits(&self) -> Vec<(String, i32, i64)> {
+        self.commits.replace(Vec::new())
+    }
+
+    /// Stops listening, so that the revocation that closing the consumer triggers on the event
+    /// loop thread does not wait for the event loop itself.
+    pub fn close(&self) {
+        self.events.borrow_mut().take();
+        self.revocation.borrow_mut().take();
+    }
+}
+
+/// Tags every message of a consumer stream with the generation it was fetched in, for
+/// `Rebalances::accepts`.
+pub struct Generations<S> {
+    stream: S,
+    rebalances: Rc<Rebalances>,
+}
+
+impl<S> Generations<S> {
+    pub fn new(stream: S, rebalances: Rc<Rebalances>) -> Generations<S> {
+        Generations {
+            stream: stream,
+            rebalances: rebalances,
+        }
+    }
+}
+
+impl<S: Stream> Stream for Generations<S> {
+    type Item = (S::Item, u64);
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Looked up before polling, so that a revocation coming meanwhile is not taken as drained
+        self.rebalances.poll_events();
+        let generation = self.rebalances.generation.get();
+        match self.stream.poll()? {
+            Async::Ready(item) => {
+                // A timed out revocation applies to the messages fetched after it
+                self.rebalances.poll_events();
+                let fetched = self.rebalances.fetched_generation.get();
+                Ok(Async::Ready(item.map(|item| (item, fetched))))
+            }
+            Async::NotReady => {
+                self.rebalances.drained(generation);
+                *self.rebalances.stream_task.borrow_mut() = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use futures::Async;
+    use futures::Future;
+    use futures::future;
+    use futures::stream::Stream;
+    use futures::sync::mpsc::unbounded;
+    use rdkafka::consumer::ConsumerContext;
+    use rdkafka::consumer::Rebalance;
+
+    use rebalance::Generations;
+    use rebalance::RebalanceContext;
+    use rebalance::RebalanceEvent;
+
+    #[test]
+    fn it_stops_owning_partitions_from_a_revocation_to_the_next_assignment() {
+        let (context, rebalances) = RebalanceContext::new(Duration::from_millis(10));
+        assert!(rebalances.accepts("input", 1, 0));
+        context.send(RebalanceEvent::Revoked(mpsc::channel().0));
+        rebalances.poll_events();
+        assert!(!rebalances.accepts("input", 0, 1));
+        assert!(rebalances.is_revoking());
+        // not before the messages fetched until then came out of the stream
+        assert!(rebalances.take_revocation().is_none());
+        rebalances.drained(1);
+        assert!(rebalances.take_revocation().is_some());
+        assert!(rebalances.take_revocation().is_none());
+        assert!(!rebalances.is_revoking());
+
+        context.send(RebalanceEvent::Assigned(vec![("input".to_owned(), 0)]));
+        rebalances.poll_events();
+        assert!(rebalances.accepts("input", 0, 1));
+        assert!(!rebalances.accepts("input", 0, 0));
+        assert!(!rebalances.accepts("input", 1, 1));
+        assert!(!rebalances.accepts("other", 0, 1));
+    }
+
+    #[test]
+    fn it_tags_messages_with_the_generation_they_were_fetched_in() {
+        let (context, rebalances) = RebalanceContext::new(Duration::from_millis(10));
+        let rebalances = Rc::new(rebalances);
+        let (sender, receiver) = unbounded::<&str>();
+        let mut messages = Generations::new(receiver, rebalances.clone());
+        future::lazy(move || {
+            sender.unbounded_send("buffered").unwrap();
+            context.send(RebalanceEvent::Revoked(mpsc::channel().0));
+            assert_eq!(messages.poll(), Ok(Async::Ready(Some(("buffered", 0)))));
+            assert!(rebalances.take_revocation().is_none());
+            assert_eq!(messages.poll(), Ok(Async::NotReady));
+            assert!(rebalances.take_revocation().is_some());
+
+            context.send(RebalanceEvent::Assigned(vec![("input".to_owned(), 0)]));
+            sender.unbounded_send("fetched again").unwrap();
+            assert_eq!(messages.poll(), Ok(Async::Ready(Some(("fetched again", 1)))));
+            assert!(rebalances.accepts("input", 0, 1));
+            Ok::<_, ()>(())
+        }).wait()
+            .unwrap();
+    }
+
+    #[test]
+    fn it_hands_acknowledged_commits_over() {
+        let (context, rebalances) = RebalanceContext::new(Duration::from_millis(10));
+        context.send(RebalanceEvent::Committed(vec![("input".to_owned(), 0, 12)]));
+        rebalances.poll_events();
+        assert_eq!(rebalances.take_commits(), vec![("input".to_owned(), 0, 12)]);
+        assert!(rebalances.take_commits().is_empty());
+    }
+
+    #[test]
+    fn it_does_not_wait_for_a_closed_event_loop() {
+        let (context, rebalances) = RebalanceContext::new(Duration::from_secs(3600));
+        rebalances.close();
+        context.pre_rebalance(&Rebalance::Revoke);
+        rebalances.poll_events();
+        assert!(rebalances.accepts("input", 0, 0));
+    }
+}
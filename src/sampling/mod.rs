@@ -0,0 +1,44 @@
+extern crate rand;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a, which unlike `DefaultHasher` is guaranteed to give the same hash on every run and
+// every Rust version.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Whether to process a message when keeping a random `ratio` of them.
+pub fn sample_randomly(ratio: f64) -> bool {
+    self::rand::random::<f64>() < ratio
+}
+
+/// Whether to process a message with this sampling key when keeping `ratio` of the keys. A key is
+/// either always kept or always dropped.
+pub fn sample_by_key(key: &[u8], ratio: f64) -> bool {
+    ratio >= 1.0 || (fnv1a(key) as f64) < ratio * (u64::max_value() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sampling::fnv1a;
+    use sampling::sample_by_key;
+
+    #[test]
+    fn it_hashes_keys_the_same_everywhere() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn it_keeps_about_the_ratio_of_keys() {
+        let keys: Vec<String> = (0..10000).map(|i| format!("\"user-{}\"", i)).collect();
+        let kept = keys.iter().filter(|key| sample_by_key(key.as_bytes(), 0.1)).count();
+        assert!(kept > 900 && kept < 1100, "kept {} keys", kept);
+        assert!(keys.iter().all(|key| sample_by_key(key.as_bytes(), 1.0)));
+    }
+}
@@ -0,0 +1,84 @@
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+// The last SIGINT or SIGTERM received, 0 if none
+static SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    SIGNAL.store(signal as usize, Ordering::SeqCst);
+}
+
+/// A shutdown request shared between the event loop and the CPU pool. The first request wins:
+/// its exit code is the one the process exits with.
+pub struct Shutdown {
+    requested: AtomicBool,
+    exit_code: AtomicIsize,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            requested: AtomicBool::new(false),
+            exit_code: AtomicIsize::new(0),
+        }
+    }
+
+    pub fn request(&self, exit_code: i32) {
+        if !self.requested.swap(true, Ordering::SeqCst) {
+            self.exit_code.store(exit_code as isize, Ordering::SeqCst);
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code.load(Ordering::SeqCst) as i32
+    }
+}
+
+/// Turns SIGINT and SIGTERM into a shutdown request, so that the pipeline stops consuming, drains,
+/// flushes and commits. If it is still running `deadline` after the signal, the process exits with
+/// `128 + signal`, like it would have without a handler.
+pub fn handle_signals(shutdown: Arc<Shutdown>, deadline: Duration) {
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+    }
+    // Only an atomic store is safe in the handler itself, the rest happens on this thread.
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        let signal = SIGNAL.load(Ordering::SeqCst);
+        if signal != 0 {
+            info!("Received signal {}, draining the pipeline", signal);
+            shutdown.request(0);
+            thread::sleep(deadline);
+            error!("Pipeline not shut down after {:?}, exiting", deadline);
+            process::exit(128 + signal as i32);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use shutdown::Shutdown;
+
+    #[test]
+    fn it_keeps_the_first_exit_code() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_requested());
+
+        shutdown.request(3);
+        shutdown.request(1);
+
+        assert!(shutdown.is_requested());
+        assert_eq!(shutdown.exit_code(), 3);
+    }
+}